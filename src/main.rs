@@ -5,7 +5,7 @@
     unused_assignments,
     unused_doc_comments
 )]
-#![feature(dropck_eyepatch)]
+#![feature(dropck_eyepatch, coerce_unsized, unsize, layout_for_ptr)]
 
 mod s1_lifetime_annotations;
 mod s2_declarative_macros;
@@ -34,9 +34,29 @@ fn main() {
             s4_smart_pointers_interior_mutability::cell::tests();
             s4_smart_pointers_interior_mutability::refcell::tests();
             s4_smart_pointers_interior_mutability::rc::tests();
+            s4_smart_pointers_interior_mutability::arc::tests();
+            s4_smart_pointers_interior_mutability::once::tests();
+            s4_smart_pointers_interior_mutability::ghost::tests();
+            s4_smart_pointers_interior_mutability::ghost_list::tests();
         }
 
-        5 => s5_channels::channels::tests(),
+        5 => {
+            s5_channels::channels::tests();
+            s5_channels::select::tests();
+            s5_channels::mpmc::tests();
+            s5_channels::lockfree::tests();
+            s5_channels::executor::tests();
+            s5_channels::async_channel::tests();
+            s5_channels::broadcast::tests();
+            s5_channels::watch::tests();
+            s5_channels::oneshot::tests();
+            s5_channels::timer::tests();
+            s5_channels::priority::tests();
+            s5_channels::ipc::tests();
+            s5_channels::spsc::tests();
+            s5_channels::stats::tests();
+            // s5_channels::benches::benchmarks::run_benchmarks();
+        }
 
         6 => {
             s6_sorting_algos::orst::tests();
@@ -0,0 +1,371 @@
+// Atomically reference counted pointer. Same idea as MyRc, but the counts are atomics so that
+// clones can be sent to (and dropped on) other threads
+use std::alloc::{dealloc, Layout};
+use std::marker::{PhantomData, Unsize};
+use std::mem::ManuallyDrop;
+use std::ops::CoerceUnsized;
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+// if someone mem::forget's clones in a loop the count could wrap around to 0 and the value would be
+// freed while still in use. Way before that happens we abort the whole process
+const MAX_REFCOUNT: usize = isize::MAX as usize;
+
+#[repr(C)]
+struct ArcInner<T: ?Sized> {
+    strong: AtomicUsize,
+    // just like MyRc, all the MyArc's together hold one weak reference
+    weak: AtomicUsize,
+    value: ManuallyDrop<T>,
+}
+
+pub struct MyArc<T: ?Sized> {
+    inner: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+pub struct MyWeak<T: ?Sized> {
+    inner: NonNull<ArcInner<T>>,
+    _marker: PhantomData<ArcInner<T>>,
+}
+
+// Sending a MyArc<T> to another thread lets that thread get a &T (so T: Sync), and it might be the
+// thread that drops the last MyArc and with it the T (so T: Send). Sharing a &MyArc<T> lets the
+// other thread clone it, which is the same thing as sending it. So both need T: Send + Sync
+unsafe impl<T: ?Sized + Send + Sync> Send for MyArc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for MyArc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Send for MyWeak<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for MyWeak<T> {}
+
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<MyArc<U>> for MyArc<T> {}
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<MyWeak<U>> for MyWeak<T> {}
+
+// the counts are accessed through the raw pointer, a MyWeak can outlive the value
+unsafe fn strong<'a, T: ?Sized>(inner: NonNull<ArcInner<T>>) -> &'a AtomicUsize {
+    &(*inner.as_ptr()).strong
+}
+
+unsafe fn weak<'a, T: ?Sized>(inner: NonNull<ArcInner<T>>) -> &'a AtomicUsize {
+    &(*inner.as_ptr()).weak
+}
+
+impl<T> MyArc<T> {
+    pub fn new(v: T) -> Self {
+        let inner = Box::new(ArcInner {
+            strong: AtomicUsize::new(1),
+            weak: AtomicUsize::new(1),
+            value: ManuallyDrop::new(v),
+        });
+
+        MyArc {
+            // SAFETY: Box does not give us a null pointer
+            inner: unsafe { NonNull::new_unchecked(Box::into_raw(inner)) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> MyArc<T> {
+    pub fn downgrade(this: &Self) -> MyWeak<T> {
+        // Relaxed: same reasoning as in clone, we already have a reference so the allocation can't
+        // go away underneath us
+        let old = unsafe { weak(this.inner) }.fetch_add(1, Ordering::Relaxed);
+
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+
+        MyWeak {
+            inner: this.inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Only a snapshot, other threads can change it right after we've read it
+    pub fn strong_count(this: &Self) -> usize {
+        unsafe { strong(this.inner) }.load(Ordering::Acquire)
+    }
+
+    /// Only a snapshot, other threads can change it right after we've read it
+    pub fn weak_count(this: &Self) -> usize {
+        // don't count the weak reference held by the MyArc's
+        unsafe { weak(this.inner) }.load(Ordering::Acquire) - 1
+    }
+
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        std::ptr::addr_eq(this.inner.as_ptr(), other.inner.as_ptr())
+    }
+}
+
+impl<T: ?Sized> Clone for MyArc<T> {
+    fn clone(&self) -> Self {
+        // Relaxed is enough here. We already hold a MyArc, so the count is at least 1 and can't
+        // drop to 0 while we're in here. We don't read or write anything else that other threads
+        // need to see in order, we're just bumping a counter
+        let old = unsafe { strong(self.inner) }.fetch_add(1, Ordering::Relaxed);
+
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+
+        MyArc {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> std::ops::Deref for MyArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the value is alive for as long as there's a MyArc
+        unsafe { &(*self.inner.as_ptr()).value }
+    }
+}
+
+impl<T: ?Sized> Drop for MyArc<T> {
+    fn drop(&mut self) {
+        // Release: everything this thread did with the value has to happen before the count goes
+        // down, otherwise the thread that drops the value could do so while we're still using it
+        if unsafe { strong(self.inner) }.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        // Acquire: pairs with the Release decrements of all the other threads, so we see
+        // everything they did with the value before we drop it. A fence instead of making the
+        // fetch_sub AcqRel, as only the last MyArc needs it
+        fence(Ordering::Acquire);
+
+        unsafe {
+            // SAFETY: we were the last MyArc. MyWeak's see strong == 0 and won't upgrade
+            ManuallyDrop::drop(&mut (*self.inner.as_ptr()).value);
+
+            // give up the weak reference held by the MyArc's
+            drop(MyWeak {
+                inner: self.inner,
+                _marker: PhantomData,
+            });
+        }
+    }
+}
+
+impl<T: ?Sized> MyWeak<T> {
+    pub fn upgrade(&self) -> Option<MyArc<T>> {
+        let strong = unsafe { strong(self.inner) };
+        let mut n = strong.load(Ordering::Relaxed);
+
+        // can't just fetch_add like clone does, as the count might be 0, and once it's 0 the value
+        // is gone and it must never go back up
+        loop {
+            if n == 0 {
+                return None;
+            }
+
+            if n > MAX_REFCOUNT {
+                std::process::abort();
+            }
+
+            // Acquire on success so that we see the value as it was when it was last released
+            match strong.compare_exchange_weak(n, n + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => {
+                    return Some(MyArc {
+                        inner: self.inner,
+                        _marker: PhantomData,
+                    })
+                }
+                Err(current) => n = current,
+            }
+        }
+    }
+
+    pub fn strong_count(&self) -> usize {
+        unsafe { strong(self.inner) }.load(Ordering::Acquire)
+    }
+}
+
+impl<T: ?Sized> Clone for MyWeak<T> {
+    fn clone(&self) -> Self {
+        let old = unsafe { weak(self.inner) }.fetch_add(1, Ordering::Relaxed);
+
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+
+        MyWeak {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for MyWeak<T> {
+    fn drop(&mut self) {
+        // same Release / Acquire dance as MyArc::drop, but for the allocation instead of the value
+        if unsafe { weak(self.inner) }.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+
+        fence(Ordering::Acquire);
+
+        unsafe {
+            dealloc(
+                self.inner.as_ptr() as *mut u8,
+                Layout::for_value_raw(self.inner.as_ptr()),
+            );
+        }
+    }
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for MyArc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+pub fn tests() {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread::spawn;
+
+    struct DropCount(&'static AtomicUsize);
+
+    impl Drop for DropCount {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn is_send_and_sync() {
+        assert_send_sync::<MyArc<String>>();
+        assert_send_sync::<MyWeak<String>>();
+        assert_send_sync::<MyArc<dyn Fn() + Send + Sync>>();
+    }
+
+    #[test]
+    fn clones_across_threads_drop_once() {
+        let drops: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+        let arc = MyArc::new(DropCount(drops));
+
+        let handles: Vec<_> = (0..10)
+            .map(|_| {
+                let arc = arc.clone();
+                spawn(move || {
+                    for _ in 0..1000 {
+                        let c = arc.clone();
+                        drop(c);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(MyArc::strong_count(&arc), 1);
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(arc);
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn last_drop_sees_other_threads_writes() {
+        // each thread writes to the value through an atomic and then drops its MyArc. Whichever
+        // thread drops last has to see all the writes, that's what the Release / Acquire pair in
+        // drop is for
+        struct CheckOnDrop(AtomicUsize);
+
+        impl Drop for CheckOnDrop {
+            fn drop(&mut self) {
+                assert_eq!(self.0.load(Ordering::Relaxed), 8);
+            }
+        }
+
+        for _ in 0..100 {
+            let arc = MyArc::new(CheckOnDrop(AtomicUsize::new(0)));
+
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let arc = arc.clone();
+                    spawn(move || {
+                        arc.0.fetch_add(1, Ordering::Relaxed);
+                    })
+                })
+                .collect();
+
+            drop(arc);
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn upgrade_races_with_last_drop() {
+        for _ in 0..100 {
+            let drops: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+            let go: &'static _ = Box::leak(Box::new(AtomicBool::new(false)));
+
+            let arc = MyArc::new(DropCount(drops));
+            let weak = MyArc::downgrade(&arc);
+
+            let upgraders: Vec<_> = (0..4)
+                .map(|_| {
+                    let weak = weak.clone();
+                    spawn(move || {
+                        while !go.load(Ordering::Acquire) {
+                            std::hint::spin_loop();
+                        }
+                        // either we get it before the last drop, and then it can't be dropped
+                        // while we hold it, or we get None
+                        if let Some(arc) = weak.upgrade() {
+                            assert_eq!(drops.load(Ordering::Relaxed), 0);
+                            drop(arc);
+                        }
+                    })
+                })
+                .collect();
+
+            go.store(true, Ordering::Release);
+            drop(arc);
+
+            for upgrader in upgraders {
+                upgrader.join().unwrap();
+            }
+
+            // every strong handle is gone now, the ones from upgrade included
+            assert!(weak.upgrade().is_none());
+            assert_eq!(weak.strong_count(), 0);
+            // whichever way the races went, and however many upgrades got in before the drop
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn unsized_coercion() {
+        let f: MyArc<dyn Fn() -> i32 + Send + Sync> = MyArc::new(|| 42);
+        let g = f.clone();
+        assert_eq!(spawn(move || g() * 2).join().unwrap(), 84);
+        assert_eq!(MyArc::strong_count(&f), 1);
+    }
+
+    #[test]
+    fn weak_counts() {
+        let arc = MyArc::new(String::from("hi"));
+        let weak = MyArc::downgrade(&arc);
+        assert_eq!(MyArc::weak_count(&arc), 1);
+        assert_eq!(*weak.upgrade().unwrap(), "hi");
+
+        drop(arc);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+    }
+}
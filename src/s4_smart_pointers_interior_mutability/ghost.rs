@@ -0,0 +1,125 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+
+// GhostCell: borrow checking for a whole bunch of cells at once, at compile time.
+//
+// MyRefCell keeps a RefState in every cell and checks it on every borrow. With GhostCell the
+// permission to access the cells is split off into a separate GhostToken, and the cells themselves
+// don't have any state at all. Having a &token gives shared access to all the cells, a &mut token
+// gives exclusive access to one of them at a time. So the regular borrow checker, checking how the
+// one token is borrowed, does what RefState does at runtime.
+//
+// The trick is making sure a token can only open its own cells, and not the cells of some other
+// token. Both are tagged with a 'brand lifetime, and every GhostToken::new makes a brand new one:
+// the closure has to work for *any* 'new_brand, so it can't be unified with any other lifetime.
+// The brand also has to be invariant, otherwise the compiler could shrink two different brands down
+// to a common one.
+//
+// ```
+// GhostToken::new(|mut token| {
+//     let cell = GhostCell::new(42);
+//
+//     let a = cell.borrow(&token);
+//     let b = cell.borrow(&token); // fine, two shared borrows of the token
+//     *cell.borrow_mut(&mut token) += 1; // error: token is already borrowed by a and b
+//     println!("{}", a + b);
+// });
+// ```
+
+// fn(&'brand ()) -> &'brand () is invariant over 'brand, and is Send + Sync, so it doesn't change
+// anything about the auto traits
+type InvariantLifetime<'brand> = PhantomData<fn(&'brand ()) -> &'brand ()>;
+
+pub struct GhostToken<'brand> {
+    _marker: InvariantLifetime<'brand>,
+}
+
+impl GhostToken<'_> {
+    /// The token only lives inside `f`, the brand can't escape
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new<R>(f: impl for<'new_brand> FnOnce(GhostToken<'new_brand>) -> R) -> R {
+        f(GhostToken {
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[repr(transparent)]
+pub struct GhostCell<'brand, T: ?Sized> {
+    _marker: InvariantLifetime<'brand>,
+    value: UnsafeCell<T>,
+}
+
+// Sending the cell sends the T. Sharing the cell lets other threads get a &T (with a shared token)
+// or a &mut T (if the token is sent over), so that needs both
+unsafe impl<T: ?Sized + Send> Send for GhostCell<'_, T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for GhostCell<'_, T> {}
+
+impl<'brand, T> GhostCell<'brand, T> {
+    pub fn new(value: T) -> Self {
+        GhostCell {
+            _marker: PhantomData,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'brand, T: ?Sized> GhostCell<'brand, T> {
+    pub fn borrow<'a>(&'a self, _token: &'a GhostToken<'brand>) -> &'a T {
+        // SAFETY: every &mut T handed out by borrow_mut holds on to a &mut of the one token with
+        // this brand, so while we hold a & of it there can't be any
+        unsafe { &*self.value.get() }
+    }
+
+    pub fn borrow_mut<'a>(&'a self, _token: &'a mut GhostToken<'brand>) -> &'a mut T {
+        // SAFETY: we hold the only &mut of the token, so no other reference to any of the cells
+        // with this brand can exist until it's given back
+        unsafe { &mut *self.value.get() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub fn tests() {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn many_cells_one_token() {
+        GhostToken::new(|mut token| {
+            let cells: Vec<_> = (0..4).map(GhostCell::new).collect();
+
+            for cell in &cells {
+                *cell.borrow_mut(&mut token) *= 10;
+            }
+
+            let a = cells[1].borrow(&token);
+            let b = cells[3].borrow(&token);
+            assert_eq!(a + b, 40);
+
+            let total: i32 = cells.into_iter().map(GhostCell::into_inner).sum();
+            assert_eq!(total, 60);
+        });
+    }
+
+    #[test]
+    fn token_can_move_to_another_thread() {
+        GhostToken::new(|mut token| {
+            let cell = GhostCell::new(String::from("hi"));
+
+            std::thread::scope(|s| {
+                s.spawn(|| cell.borrow_mut(&mut token).push('!'));
+            });
+
+            assert_eq!(cell.borrow(&token), "hi!");
+        });
+    }
+}
@@ -0,0 +1,237 @@
+#![forbid(unsafe_code)]
+// A doubly linked list on top of MyRc<GhostCell<..>>. All the unsafe is in MyRc and GhostCell, the
+// list itself has none (the forbid above makes sure of that).
+//
+// With MyRefCell instead of GhostCell every step through the list would be a runtime borrow check.
+// Here, walking the list only needs a &GhostToken, and changing it a &mut GhostToken.
+//
+// next pointers are MyRc's, prev pointers are MyWeak's, so there are no cycles and dropping the list
+// frees every node.
+
+use super::ghost::{GhostCell, GhostToken};
+use super::rc::{MyRc, MyWeak};
+
+pub type NodeRef<'brand, T> = MyRc<GhostCell<'brand, Node<'brand, T>>>;
+type WeakNodeRef<'brand, T> = MyWeak<GhostCell<'brand, Node<'brand, T>>>;
+
+pub struct Node<'brand, T> {
+    pub value: T,
+    prev: Option<WeakNodeRef<'brand, T>>,
+    next: Option<NodeRef<'brand, T>>,
+}
+
+pub struct GhostList<'brand, T> {
+    head: Option<NodeRef<'brand, T>>,
+    tail: Option<NodeRef<'brand, T>>,
+    len: usize,
+}
+
+impl<'brand, T> GhostList<'brand, T> {
+    pub fn new() -> Self {
+        GhostList {
+            head: None,
+            tail: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_back(&mut self, value: T, token: &mut GhostToken<'brand>) {
+        let node = MyRc::new(GhostCell::new(Node {
+            value,
+            prev: self.tail.as_ref().map(MyRc::downgrade),
+            next: None,
+        }));
+
+        match self.tail.take() {
+            Some(tail) => tail.borrow_mut(token).next = Some(node.clone()),
+            None => self.head = Some(node.clone()),
+        }
+
+        self.tail = Some(node);
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, value: T, token: &mut GhostToken<'brand>) {
+        let node = MyRc::new(GhostCell::new(Node {
+            value,
+            prev: None,
+            next: self.head.clone(),
+        }));
+
+        match self.head.take() {
+            Some(head) => head.borrow_mut(token).prev = Some(MyRc::downgrade(&node)),
+            None => self.tail = Some(node.clone()),
+        }
+
+        self.head = Some(node);
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self, token: &mut GhostToken<'brand>) -> Option<T> {
+        let head = self.head.take()?;
+
+        match head.borrow_mut(token).next.take() {
+            Some(next) => {
+                next.borrow_mut(token).prev = None;
+                self.head = Some(next);
+            }
+            None => self.tail = None,
+        }
+
+        self.len -= 1;
+        Some(Self::into_value(head))
+    }
+
+    pub fn pop_back(&mut self, token: &mut GhostToken<'brand>) -> Option<T> {
+        let tail = self.tail.take()?;
+
+        match tail.borrow_mut(token).prev.take().and_then(|p| p.upgrade()) {
+            Some(prev) => {
+                // this is the last MyRc to the old tail (apart from `tail` itself)
+                prev.borrow_mut(token).next = None;
+                self.tail = Some(prev);
+            }
+            None => self.head = None,
+        }
+
+        self.len -= 1;
+        Some(Self::into_value(tail))
+    }
+
+    // once a node is unlinked nothing else has a MyRc to it. The MyWeak pointing to it from its old
+    // neighbour (if any) doesn't stop try_unwrap
+    fn into_value(node: NodeRef<'brand, T>) -> T {
+        match MyRc::try_unwrap(node) {
+            Ok(cell) => cell.into_inner().value,
+            Err(_) => unreachable!("unlinked node is still referenced"),
+        }
+    }
+
+    pub fn iter<'a>(&'a self, token: &'a GhostToken<'brand>) -> Iter<'a, 'brand, T> {
+        Iter {
+            next: self.head.as_ref(),
+            token,
+        }
+    }
+
+    /// Walk the list back to front, following the prev pointers
+    pub fn for_each_rev(&self, token: &GhostToken<'brand>, mut f: impl FnMut(&T)) {
+        let mut current = self.tail.clone();
+
+        while let Some(node) = current {
+            let node = node.borrow(token);
+            f(&node.value);
+            current = node.prev.as_ref().and_then(|p| p.upgrade());
+        }
+    }
+
+    pub fn for_each_mut(&self, token: &mut GhostToken<'brand>, mut f: impl FnMut(&mut T)) {
+        let mut current = self.head.clone();
+
+        // only one node is borrowed through the token at a time, so this is one &mut after another
+        while let Some(node) = current {
+            let node = node.borrow_mut(token);
+            f(&mut node.value);
+            current = node.next.clone();
+        }
+    }
+}
+
+impl<T> Default for GhostList<'_, T> {
+    fn default() -> Self {
+        GhostList::new()
+    }
+}
+
+impl<T> Drop for GhostList<'_, T> {
+    fn drop(&mut self) {
+        // without a token we can't unlink the nodes one by one, so this drops recursively through
+        // the next pointers. Fine for an example, a very long list would overflow the stack
+        self.tail.take();
+        self.head.take();
+    }
+}
+
+pub struct Iter<'a, 'brand, T> {
+    next: Option<&'a NodeRef<'brand, T>>,
+    token: &'a GhostToken<'brand>,
+}
+
+impl<'a, T> Iterator for Iter<'a, '_, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.next?.borrow(self.token);
+        self.next = node.next.as_ref();
+        Some(&node.value)
+    }
+}
+
+pub fn tests() {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::cell::MyCell;
+    use super::*;
+
+    #[test]
+    fn push_iterate_and_pop() {
+        GhostToken::new(|mut token| {
+            let mut list = GhostList::new();
+            list.push_back(2, &mut token);
+            list.push_back(3, &mut token);
+            list.push_front(1, &mut token);
+            assert_eq!(list.len(), 3);
+
+            // any number of iterators at once, they only need a shared token
+            let forward: Vec<_> = list.iter(&token).collect();
+            let sum: i32 = list.iter(&token).sum();
+            assert_eq!(forward, [&1, &2, &3]);
+            assert_eq!(sum, 6);
+
+            list.for_each_mut(&mut token, |v| *v *= 10);
+
+            let mut backward = vec![];
+            list.for_each_rev(&token, |v| backward.push(*v));
+            assert_eq!(backward, [30, 20, 10]);
+
+            assert_eq!(list.pop_back(&mut token), Some(30));
+            assert_eq!(list.pop_front(&mut token), Some(10));
+            assert_eq!(list.pop_back(&mut token), Some(20));
+            assert_eq!(list.pop_back(&mut token), None);
+            assert!(list.is_empty());
+        });
+    }
+
+    struct Counted<'a>(&'a MyCell<usize>);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn dropping_the_list_frees_every_node() {
+        let drops = MyCell::new(0);
+
+        GhostToken::new(|mut token| {
+            let mut list = GhostList::new();
+            for _ in 0..5 {
+                list.push_back(Counted(&drops), &mut token);
+            }
+            drop(list.pop_front(&mut token));
+            assert_eq!(drops.get(), 1);
+        });
+
+        assert_eq!(drops.get(), 5);
+    }
+}
@@ -0,0 +1,470 @@
+// Leak and cycle detection for MyRc, only compiled with the rc-leak-detector feature.
+//
+// Every RcInner that gets allocated is put in a global registry together with the name of the type
+// it holds and a backtrace of where it was created, and is taken out again when it's freed. Whatever
+// is still in there at some checkpoint (say, the end of a test) has leaked, most likely because of a
+// reference cycle.
+//
+// Cycles can also be cleaned up, for types that implement Trace and were created with
+// MyRc::new_traced. The collector does trial deletion: for every traced allocation, subtract the
+// references that come from other traced allocations from its strong count. Whatever is left over
+// has to come from outside (a variable on the stack, an untraced MyRc, ...), so those allocations,
+// and everything reachable from them, are alive. The rest can only be reached from each other, so
+// they're garbage cycles.
+//
+// MyRc is !Send, so an allocation never leaves the thread that made it. Everything here only looks
+// at the allocations of the current thread, which also keeps tests running in parallel out of each
+// other's way.
+//
+// Backtraces are captured with Backtrace::capture, so they're only there when RUST_BACKTRACE or
+// RUST_LIB_BACKTRACE is set.
+
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+
+use super::rc::{counts_at, MyRc};
+use super::refcell::MyRefCell;
+
+/// Implemented by types that hold MyRc's, so the collector can find the edges between allocations.
+///
+/// # Safety
+///
+/// `trace` must report every MyRc the value owns exactly once, and nothing else (not the MyWeak's,
+/// they don't keep anything alive, and not MyRc's it only borrows). Reporting too few is fine, it
+/// only makes the collector more conservative. Reporting one too many times is not: the collector
+/// would take those reports for references that keep the allocation alive from inside a cycle,
+/// and free it while something outside still points to it
+pub unsafe trait Trace {
+    fn trace(&self, tracer: &mut Tracer<'_>);
+}
+
+pub struct Tracer<'a> {
+    visit: &'a mut dyn FnMut(usize),
+}
+
+impl Tracer<'_> {
+    pub fn visit<T: ?Sized>(&mut self, rc: &MyRc<T>) {
+        (self.visit)(MyRc::addr(rc) as usize)
+    }
+}
+
+unsafe impl<T: ?Sized> Trace for MyRc<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        // only the edge, the collector looks at the allocation itself through the registry
+        tracer.visit(self);
+    }
+}
+
+unsafe impl<T: Trace> Trace for MyRefCell<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        // if it's mutably borrowed right now we can't look inside. Not reporting the children just
+        // means that they look like they're referenced from outside, so they won't be collected
+        if let Ok(value) = self.try_borrow() {
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        for value in self {
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<T: Trace + ?Sized> Trace for Box<T> {
+    fn trace(&self, tracer: &mut Tracer<'_>) {
+        (**self).trace(tracer);
+    }
+}
+
+// What the collector needs to know about a traced allocation, filled in by MyRc::new_traced
+#[derive(Copy, Clone)]
+pub(super) struct TraceOps {
+    pub(super) trace: unsafe fn(*const u8, &mut Tracer<'_>),
+    pub(super) drop_value: unsafe fn(*const u8),
+    pub(super) release: unsafe fn(*const u8),
+}
+
+struct Allocation {
+    id: u64,
+    thread: ThreadId,
+    type_name: &'static str,
+    backtrace: Backtrace,
+    ops: Option<TraceOps>,
+}
+
+// keyed by the address of the RcInner. Addresses are reused, but only after unregister
+static REGISTRY: Mutex<BTreeMap<usize, Allocation>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn register(addr: *const u8, type_name: &'static str) {
+    let allocation = Allocation {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        thread: thread::current().id(),
+        type_name,
+        backtrace: Backtrace::capture(),
+        ops: None,
+    };
+
+    REGISTRY.lock().unwrap().insert(addr as usize, allocation);
+}
+
+pub(super) fn set_trace_ops(addr: *const u8, ops: TraceOps) {
+    if let Some(allocation) = REGISTRY.lock().unwrap().get_mut(&(addr as usize)) {
+        allocation.ops = Some(ops);
+    }
+}
+
+pub(super) fn unregister(addr: *const u8) {
+    REGISTRY.lock().unwrap().remove(&(addr as usize));
+}
+
+/// An allocation that was still alive when it was reported
+pub struct LeakReport {
+    pub type_name: &'static str,
+    pub strong: usize,
+    pub weak: usize,
+    pub backtrace: String,
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "MyRc<{}> (strong = {}, weak = {}) allocated at:",
+            self.type_name, self.strong, self.weak
+        )?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+impl fmt::Debug for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Everything allocated on this thread after a checkpoint is made
+#[derive(Copy, Clone)]
+pub struct Checkpoint {
+    id: u64,
+}
+
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint {
+        id: NEXT_ID.load(Ordering::Relaxed),
+    }
+}
+
+impl Checkpoint {
+    /// Allocations made on this thread since the checkpoint that are still alive
+    pub fn leaks(&self) -> Vec<LeakReport> {
+        reports(self.id)
+    }
+
+    /// Panic with the list of leaks if there are any
+    #[track_caller]
+    pub fn assert_no_leaks(&self) {
+        let leaks = self.leaks();
+
+        if !leaks.is_empty() {
+            let report: Vec<_> = leaks.iter().map(|l| l.to_string()).collect();
+            panic!(
+                "{} MyRc allocation(s) leaked:\n{}",
+                leaks.len(),
+                report.join("\n")
+            );
+        }
+    }
+}
+
+/// All the allocations made on this thread that are still alive
+pub fn report_leaks() -> Vec<LeakReport> {
+    reports(0)
+}
+
+fn reports(since: u64) -> Vec<LeakReport> {
+    let thread = thread::current().id();
+    let registry = REGISTRY.lock().unwrap();
+
+    registry
+        .iter()
+        .filter(|(_, a)| a.thread == thread && a.id >= since)
+        .map(|(&addr, a)| {
+            // SAFETY: it's in the registry, so it hasn't been freed yet. And it's from this thread,
+            // so nobody is changing the counts while we read them
+            let (strong, weak) = unsafe { counts_at(addr as *const u8) };
+
+            LeakReport {
+                type_name: a.type_name,
+                strong: strong.get(),
+                weak: weak.get(),
+                backtrace: a.backtrace.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Find reference cycles among the traced allocations of this thread that can't be reached from
+/// anywhere else anymore, drop their values and free them. Returns how many allocations were freed.
+///
+/// If some Trace impl reports more references to an allocation than it has, nothing is collected
+/// at all: the counts can't be trusted anymore, so neither can what looks like garbage.
+///
+/// The values are dropped in no particular order, while the others in the same cycle are still
+/// around. A Drop impl must not upgrade a MyWeak to reach another value in its cycle, it might have
+/// been dropped already
+pub fn collect_cycles() -> usize {
+    let thread = thread::current().id();
+
+    // copy out what we need, the registry can't stay locked: tracing runs user code, and dropping
+    // values frees allocations, which unregisters them
+    let candidates: Vec<(usize, TraceOps)> = REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, a)| a.thread == thread)
+        .filter_map(|(&addr, a)| a.ops.map(|ops| (addr, ops)))
+        // SAFETY: still registered, so still allocated. strong == 0 means the value is gone already
+        .filter(|&(addr, _)| unsafe { counts_at(addr as *const u8) }.0.get() > 0)
+        .collect();
+
+    let traced: HashSet<usize> = candidates.iter().map(|&(addr, _)| addr).collect();
+
+    // SAFETY (for all the unsafe blocks below): every candidate is a live allocation from this
+    // thread with strong > 0, and none of them are freed until the very end.
+    //
+    // Every candidate is traced once, up front. Tracing runs user code, which could report
+    // something different the second time around, and then the two steps below wouldn't agree on
+    // what points where
+    let children: HashMap<usize, Vec<usize>> = candidates
+        .iter()
+        .map(|&(addr, ops)| {
+            let mut children = vec![];
+            let mut tracer = Tracer {
+                visit: &mut |child| {
+                    if traced.contains(&child) {
+                        children.push(child)
+                    }
+                },
+            };
+            unsafe { (ops.trace)(addr as *const u8, &mut tracer) };
+            (addr, children)
+        })
+        .collect();
+
+    // 1. trial deletion: take away the references that come from other candidates
+    let mut external: HashMap<usize, usize> = candidates
+        .iter()
+        .map(|&(addr, _)| (addr, unsafe { counts_at(addr as *const u8) }.0.get()))
+        .collect();
+
+    for child in children.values().flatten() {
+        let count = external.get_mut(child).unwrap();
+        match count.checked_sub(1) {
+            Some(left) => *count = left,
+            // more references reported than there are, some Trace impl is lying. Give up
+            None => return 0,
+        }
+    }
+
+    // 2. whatever still has references left is reachable from outside, and so is everything it
+    // points to
+    let mut alive = HashSet::new();
+    let mut stack: Vec<usize> = external
+        .iter()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(&addr, _)| addr)
+        .collect();
+
+    while let Some(addr) = stack.pop() {
+        if alive.insert(addr) {
+            stack.extend(&children[&addr]);
+        }
+    }
+
+    let garbage: Vec<(usize, TraceOps)> = candidates
+        .into_iter()
+        .filter(|(addr, _)| !alive.contains(addr))
+        .collect();
+
+    // 3. hold on to every garbage allocation with an extra strong count, so that dropping one
+    // value (and with it the MyRc's to the others) can't bring another one down to 0 and have
+    // MyRc::drop drop it a second time
+    for &(addr, _) in &garbage {
+        let strong = unsafe { counts_at(addr as *const u8) }.0;
+        strong.set(strong.get() + 1);
+    }
+
+    for &(addr, ops) in &garbage {
+        unsafe { (ops.drop_value)(addr as *const u8) };
+    }
+
+    // now the only strong reference left to each of them is ours
+    for &(addr, ops) in &garbage {
+        debug_assert_eq!(unsafe { counts_at(addr as *const u8) }.0.get(), 1);
+        unsafe { (ops.release)(addr as *const u8) };
+    }
+
+    garbage.len()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::cell::MyCell;
+    use super::*;
+
+    struct Node {
+        next: MyRefCell<Option<MyRc<Node>>>,
+        drops: MyRc<MyCell<usize>>,
+    }
+
+    // SAFETY: next is the only MyRc that can point to another node. drops is never traced, so it
+    // can't be part of a cycle, which is fine
+    unsafe impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer<'_>) {
+            self.next.trace(tracer);
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn node(drops: &MyRc<MyCell<usize>>) -> MyRc<Node> {
+        MyRc::new_traced(Node {
+            next: MyRefCell::new(None),
+            drops: drops.clone(),
+        })
+    }
+
+    #[test]
+    fn reports_what_is_still_alive() {
+        let checkpoint = checkpoint();
+
+        let a = MyRc::new(1);
+        let s: MyRc<str> = MyRc::from("hello");
+        let leaks = checkpoint.leaks();
+        assert_eq!(leaks.len(), 2);
+        assert!(leaks.iter().any(|l| l.type_name == "i32" && l.strong == 1));
+        assert!(leaks.iter().any(|l| l.type_name == "[u8]"));
+
+        let weak = MyRc::downgrade(&a);
+        drop(a);
+        // the value is gone, but the allocation is still there for the MyWeak
+        let leaks = checkpoint.leaks();
+        assert!(leaks
+            .iter()
+            .any(|l| l.type_name == "i32" && l.strong == 0 && l.weak == 1));
+
+        drop((weak, s));
+        checkpoint.assert_no_leaks();
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let drops = MyRc::new(MyCell::new(0));
+        let checkpoint = checkpoint();
+
+        let a = node(&drops);
+        let b = node(&drops);
+        *a.next.borrow_mut() = Some(b.clone());
+        *b.next.borrow_mut() = Some(a.clone());
+
+        // still referenced from the stack, nothing to collect
+        assert_eq!(collect_cycles(), 0);
+
+        // a self loop, only kept alive through `c`
+        let c = node(&drops);
+        *c.next.borrow_mut() = Some(c.clone());
+
+        drop((a, b, c));
+        assert_eq!(checkpoint.leaks().len(), 3);
+
+        assert_eq!(collect_cycles(), 3);
+        assert_eq!(drops.get(), 3);
+        checkpoint.assert_no_leaks();
+    }
+
+    #[test]
+    fn keeps_what_is_reachable_from_outside() {
+        let drops = MyRc::new(MyCell::new(0));
+        let checkpoint = checkpoint();
+
+        // a -> b -> c -> b, and only a is referenced from outside (through an untraced MyRc)
+        let a = node(&drops);
+        let b = node(&drops);
+        let c = node(&drops);
+        *a.next.borrow_mut() = Some(b.clone());
+        *b.next.borrow_mut() = Some(c.clone());
+        *c.next.borrow_mut() = Some(b.clone());
+        let holder = MyRc::new(a);
+        drop((b, c));
+
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(drops.get(), 0);
+
+        drop(holder);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(collect_cycles(), 2);
+        assert_eq!(drops.get(), 3);
+        checkpoint.assert_no_leaks();
+    }
+
+    // reports its MyRc more often than it holds it
+    struct Liar {
+        next: MyRefCell<Option<MyRc<Liar>>>,
+        times: usize,
+    }
+
+    // SAFETY: it isn't, that's the point. The test only makes sure the collector notices
+    unsafe impl Trace for Liar {
+        fn trace(&self, tracer: &mut Tracer<'_>) {
+            for _ in 0..self.times {
+                self.next.trace(tracer);
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_when_trace_over_reports() {
+        let checkpoint = checkpoint();
+
+        // a is held on the stack and by b, so its strong count is 2. b reports a three times
+        let a = MyRc::new_traced(Liar {
+            next: MyRefCell::new(None),
+            times: 1,
+        });
+        let b = MyRc::new_traced(Liar {
+            next: MyRefCell::new(Some(a.clone())),
+            times: 3,
+        });
+        *a.next.borrow_mut() = Some(b.clone());
+        drop(b);
+
+        assert_eq!(collect_cycles(), 0);
+        assert_eq!(MyRc::strong_count(&a), 2);
+        assert_eq!(checkpoint.leaks().len(), 2);
+
+        // break the cycle by hand so the test doesn't leak
+        a.next.borrow_mut().take();
+        drop(a);
+        checkpoint.assert_no_leaks();
+    }
+}
@@ -1,3 +1,9 @@
+pub mod arc;
 pub mod cell;
+pub mod ghost;
+pub mod ghost_list;
+#[cfg(feature = "rc-leak-detector")]
+pub mod leak_detector;
+pub mod once;
 pub mod rc;
 pub mod refcell;
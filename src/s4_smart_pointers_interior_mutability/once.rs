@@ -0,0 +1,405 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, Thread, ThreadId};
+
+// A cell that can be written to only once. Unlike MyCell, get can hand out a &T: once the value is
+// set it can never be changed or taken out again (without a &mut), so the reference stays valid for
+// as long as the cell does
+pub struct MyOnceCell<T> {
+    value: UnsafeCell<Option<T>>,
+}
+
+impl<T> MyOnceCell<T> {
+    pub fn new() -> Self {
+        MyOnceCell {
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // SAFETY: the only write through a shared reference is in set, and that only happens while
+        // the value is None, so no &T can be out there
+        unsafe { &*self.value.get() }.as_ref()
+    }
+
+    /// Set the value if the cell is empty, otherwise hand `value` back
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.get().is_some() {
+            return Err(value);
+        }
+
+        // SAFETY: the cell is empty, so no references to the value exist. And it's !Sync (because
+        // of the UnsafeCell) so no other thread could be setting it at the same time
+        unsafe { *self.value.get() = Some(value) };
+        Ok(())
+    }
+
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// If `f` fails, the cell stays empty and the next call gets to try again
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+
+        let value = f()?;
+
+        // f could have called get_or_init on this very cell (through some other reference to it),
+        // so the cell might not be empty anymore. Overwriting it would invalidate the &T that call
+        // returned, and silently keeping one of the two values would be confusing
+        if self.set(value).is_err() {
+            panic!("reentrant init");
+        }
+
+        Ok(self.get().unwrap())
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.value.get_mut().as_mut()
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        self.value.get_mut().take()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+}
+
+impl<T> Default for MyOnceCell<T> {
+    fn default() -> Self {
+        MyOnceCell::new()
+    }
+}
+
+// A value that's computed the first time it's used
+pub struct MyLazyCell<T, F = fn() -> T> {
+    cell: MyOnceCell<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+impl<T, F: FnOnce() -> T> MyLazyCell<T, F> {
+    pub fn new(f: F) -> Self {
+        MyLazyCell {
+            cell: MyOnceCell::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| {
+            // SAFETY: we only get here while the cell is empty, and only once unless f panicked.
+            // Nothing else touches init
+            let f = unsafe { (*this.init.get()).take() };
+
+            match f {
+                Some(f) => f(),
+                // f panicked the last time, there's nothing left to run
+                None => panic!("MyLazyCell instance has previously been poisoned"),
+            }
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> std::ops::Deref for MyLazyCell<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        MyLazyCell::force(self)
+    }
+}
+
+// The thread safe version of MyOnceCell. The state goes INCOMPLETE -> RUNNING -> COMPLETE. Only the
+// thread that moves it to RUNNING runs the closure, all the others park until it's done. If the
+// closure panics the state goes back to INCOMPLETE and one of the waiting threads gets to try
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct MyOnceLock<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    // only touched on the slow path, when there's contention on the first initialization
+    waiters: Mutex<Waiters>,
+}
+
+struct Waiters {
+    // who's running the closure right now, to catch reentrant initialization, which would
+    // otherwise park the thread forever waiting for itself
+    initializer: Option<ThreadId>,
+    parked: Vec<Thread>,
+}
+
+// Sharing it lets other threads get a &T (T: Sync), and the value might be set by another thread
+// than the one that drops it (T: Send)
+unsafe impl<T: Send + Sync> Sync for MyOnceLock<T> {}
+unsafe impl<T: Send> Send for MyOnceLock<T> {}
+
+impl<T> MyOnceLock<T> {
+    pub const fn new() -> Self {
+        MyOnceLock {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: Mutex::new(Waiters {
+                initializer: None,
+                parked: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // Acquire pairs with the Release store of COMPLETE, so that we see the value that was
+        // written before it
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            // SAFETY: COMPLETE means the value has been written and will never be written again
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+
+        match value {
+            // our closure didn't run, someone else got there first
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Runs `f` at most once across all threads, unless it fails or panics, in which case the
+    /// next caller (or one of the threads waiting for it) gets to try again
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let mut f = Some(f);
+
+        loop {
+            if let Some(value) = self.get() {
+                return Ok(value);
+            }
+
+            match self.state.compare_exchange(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.waiters.lock().unwrap().initializer = Some(thread::current().id());
+
+                    // if f panics, put things back the way they were so the cell isn't stuck in
+                    // RUNNING forever
+                    let guard = ResetOnUnwind { lock: self };
+                    let result = (f.take().unwrap())();
+                    std::mem::forget(guard);
+
+                    return match result {
+                        Ok(value) => {
+                            // SAFETY: we're the only one that got to move the state to RUNNING, so
+                            // nobody else is touching the value
+                            unsafe { (*self.value.get()).write(value) };
+                            self.finish(COMPLETE);
+                            Ok(self.get().unwrap())
+                        }
+                        Err(e) => {
+                            self.finish(INCOMPLETE);
+                            Err(e)
+                        }
+                    };
+                }
+
+                Err(COMPLETE) => continue,
+                Err(_) => self.wait(),
+            }
+        }
+    }
+
+    // park until whoever is running the closure is done
+    fn wait(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        if waiters.initializer == Some(thread::current().id()) {
+            drop(waiters);
+            panic!("reentrant init");
+        }
+
+        // checked while holding the lock. finish takes the lock after changing the state, so
+        // either we see the new state here, or finish sees us in `parked` and unparks us
+        if self.state.load(Ordering::Acquire) != RUNNING {
+            return;
+        }
+
+        waiters.parked.push(thread::current());
+        drop(waiters);
+
+        // park can wake up spuriously, the caller loops and checks the state again anyway
+        while self.state.load(Ordering::Acquire) == RUNNING {
+            thread::park();
+        }
+    }
+
+    fn finish(&self, state: u8) {
+        // Release pairs with the Acquire loads in get, publishing the value we just wrote
+        self.state.store(state, Ordering::Release);
+
+        let mut waiters = self.waiters.lock().unwrap();
+        waiters.initializer = None;
+
+        for thread in waiters.parked.drain(..) {
+            thread.unpark();
+        }
+    }
+
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        if *self.state.get_mut() == COMPLETE {
+            *self.state.get_mut() = INCOMPLETE;
+            // SAFETY: it was COMPLETE, and now it's not, so nobody will read it again
+            Some(unsafe { self.value.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Default for MyOnceLock<T> {
+    fn default() -> Self {
+        MyOnceLock::new()
+    }
+}
+
+impl<T> Drop for MyOnceLock<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            // SAFETY: it's initialized, and we have a &mut so nobody is looking at it
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+struct ResetOnUnwind<'a, T> {
+    lock: &'a MyOnceLock<T>,
+}
+
+impl<T> Drop for ResetOnUnwind<'_, T> {
+    fn drop(&mut self) {
+        self.lock.finish(INCOMPLETE);
+    }
+}
+
+pub fn tests() {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread::spawn;
+
+    #[test]
+    fn once_cell() {
+        let c = MyOnceCell::new();
+        assert!(c.get().is_none());
+        assert_eq!(c.get_or_try_init(|| Err::<i32, _>("nope")), Err("nope"));
+        assert!(c.get().is_none());
+
+        assert_eq!(*c.get_or_init(|| 1), 1);
+        assert_eq!(*c.get_or_init(|| 2), 1);
+        assert_eq!(c.set(3), Err(3));
+        assert_eq!(c.into_inner(), Some(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant init")]
+    fn once_cell_reentrant_init() {
+        let c = MyOnceCell::new();
+        c.get_or_init(|| *c.get_or_init(|| 1) + 1);
+    }
+
+    #[test]
+    fn lazy_cell() {
+        let calls = std::cell::Cell::new(0);
+        let lazy = MyLazyCell::new(|| {
+            calls.set(calls.get() + 1);
+            String::from("hello")
+        });
+
+        assert_eq!(calls.get(), 0);
+        assert_eq!(lazy.len(), 5);
+        assert_eq!(*lazy, "hello");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn once_lock_runs_the_closure_once() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LOCK: MyOnceLock<String> = MyOnceLock::new();
+
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                spawn(move || {
+                    LOCK.get_or_init(|| {
+                        CALLS.fetch_add(1, Ordering::Relaxed);
+                        // give the other threads time to pile up and park
+                        thread::sleep(std::time::Duration::from_millis(50));
+                        format!("thread {}", i)
+                    })
+                })
+            })
+            .collect();
+
+        let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|v| std::ptr::eq(*v, LOCK.get().unwrap())));
+    }
+
+    #[test]
+    fn once_lock_retries_after_a_panic() {
+        let lock: &'static _ = Box::leak(Box::new(MyOnceLock::new()));
+
+        let panicked = spawn(move || lock.get_or_init(|| panic!("first try fails"))).join();
+        assert!(panicked.is_err());
+        assert!(lock.get().is_none());
+
+        assert_eq!(*lock.get_or_init(|| 42), 42);
+        assert_eq!(lock.set(0), Err(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant init")]
+    fn once_lock_reentrant_init() {
+        let lock = MyOnceLock::new();
+        lock.get_or_init(|| *lock.get_or_init(|| 1) + 1);
+    }
+}
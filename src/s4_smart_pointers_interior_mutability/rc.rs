@@ -3,7 +3,12 @@
 #![allow(unused_variables)]
 // Reference counted pointer
 use super::cell::MyCell;
-use std::marker::PhantomData;
+#[cfg(feature = "rc-leak-detector")]
+use super::leak_detector::{self, Trace, TraceOps, Tracer};
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::marker::{PhantomData, Unsize};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ops::CoerceUnsized;
 use std::ptr::NonNull;
 
 // ============================ THE DROP CHECK =========================================
@@ -34,81 +39,763 @@ fn bad_things_happen() {
 
 // ============================ THE DROP CHECK =========================================
 
-struct RcInner<T> {
-    value: T,
-    refcount: MyCell<usize>,
+// repr(C) so that RcInner<MaybeUninit<T>> (used by new_cyclic) has the same layout as RcInner<T>,
+// and so that we can compute the layout of an RcInner<[T]> ourselves (see allocate_for_slice)
+#[repr(C)]
+struct RcInner<T: ?Sized> {
+    strong: MyCell<usize>,
+    // all the MyRc's together hold one weak reference. That way the allocation stays around for
+    // as long as there's either a MyRc or a MyWeak, but the value only lives as long as the MyRc's
+    weak: MyCell<usize>,
+    // ManuallyDrop as the value is dropped when the last MyRc goes away, which can be long before
+    // the RcInner itself is deallocated by the last MyWeak
+    value: ManuallyDrop<T>,
 }
 
-pub struct MyRc<T> {
+pub struct MyRc<T: ?Sized> {
     // T is stored on the heap. It needs to be on the heap as multiple functions can be pointing to
     // T so T cannot be on the stack frame of any particular function
     inner: NonNull<RcInner<T>>,
     _marker: PhantomData<RcInner<T>>, // treat as if MyRc contains T
 }
 
+/// A non owning pointer to the value in a MyRc. Does not keep the value alive, only the allocation
+/// so that it can be checked whether the value is still around. Used to break reference cycles, for
+/// example, a child pointing back to its parent
+pub struct MyWeak<T: ?Sized> {
+    inner: NonNull<RcInner<T>>,
+    _marker: PhantomData<RcInner<T>>,
+}
+
+// the counts are accessed through the raw pointer rather than through a &RcInner<T>, since a
+// MyWeak can outlive the value, and we don't want to make a reference to an already dropped T
+unsafe fn strong<'a, T: ?Sized>(inner: NonNull<RcInner<T>>) -> &'a MyCell<usize> {
+    &(*inner.as_ptr()).strong
+}
+
+unsafe fn weak<'a, T: ?Sized>(inner: NonNull<RcInner<T>>) -> &'a MyCell<usize> {
+    &(*inner.as_ptr()).weak
+}
+
 impl<T> MyRc<T> {
     pub fn new(v: T) -> Self {
         let inner = Box::new(RcInner {
-            value: v,
-            refcount: MyCell::new(1),
+            strong: MyCell::new(1),
+            weak: MyCell::new(1),
+            value: ManuallyDrop::new(v),
         });
 
+        // SAFETY: Box does not give us a null pointer
+        let inner = unsafe { NonNull::new_unchecked(Box::into_raw(inner)) };
+
+        #[cfg(feature = "rc-leak-detector")]
+        leak_detector::register(inner.as_ptr() as *const u8, std::any::type_name::<T>());
+
+        MyRc {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Construct a value that holds a MyWeak to itself. The MyWeak given to `data_fn` can't be
+    /// upgraded until new_cyclic returns, as there's no value to point to yet
+    pub fn new_cyclic<F>(data_fn: F) -> Self
+    where
+        F: FnOnce(&MyWeak<T>) -> T,
+    {
+        // strong = 0 so any upgrade during data_fn fails
+        let uninit = Box::new(RcInner {
+            strong: MyCell::new(0),
+            weak: MyCell::new(1),
+            value: ManuallyDrop::new(MaybeUninit::<T>::uninit()),
+        });
+
+        // SAFETY: Box does not give us a null pointer, and RcInner<MaybeUninit<T>> has the same
+        // layout as RcInner<T> as it's repr(C)
+        let inner: NonNull<RcInner<T>> =
+            unsafe { NonNull::new_unchecked(Box::into_raw(uninit)).cast() };
+
+        #[cfg(feature = "rc-leak-detector")]
+        leak_detector::register(inner.as_ptr() as *const u8, std::any::type_name::<T>());
+
+        // this weak is the one that's held by all the MyRc's together. If data_fn panics, dropping
+        // it frees the allocation without touching the (uninitialized) value
+        let weak = MyWeak {
+            inner,
+            _marker: PhantomData,
+        };
+
+        let data = data_fn(&weak);
+
         unsafe {
-            MyRc {
-                // SAFETY: Box does not give us a null pointer
-                inner: NonNull::new_unchecked(Box::into_raw(inner)),
+            // SAFETY: strong is 0, so nothing could have read the value yet
+            std::ptr::addr_of_mut!((*inner.as_ptr()).value).write(ManuallyDrop::new(data));
+            strong(inner).set(1);
+        }
+
+        // hand the weak reference over to the MyRc's
+        std::mem::forget(weak);
+
+        MyRc {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> MyRc<T> {
+    pub fn downgrade(this: &Self) -> MyWeak<T> {
+        let weak = unsafe { weak(this.inner) };
+        weak.set(weak.get() + 1);
+
+        MyWeak {
+            inner: this.inner,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn strong_count(this: &Self) -> usize {
+        unsafe { strong(this.inner) }.get()
+    }
+
+    /// Number of MyWeak's pointing to this allocation
+    pub fn weak_count(this: &Self) -> usize {
+        // don't count the weak reference held by the MyRc's
+        unsafe { weak(this.inner) }.get() - 1
+    }
+
+    /// Mutable access to the value, but only if there are no other MyRc's or MyWeak's that could
+    /// see it
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if MyRc::is_unique(this) {
+            // SAFETY: we're the only pointer to the value and we have a &mut to it
+            Some(unsafe { &mut *(*this.inner.as_ptr()).value })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the two MyRc's point to the same allocation (and not just to equal values)
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        // only compare the addresses, the metadata of a dyn might differ even for the same object
+        std::ptr::addr_eq(this.inner.as_ptr(), other.inner.as_ptr())
+    }
+
+    fn is_unique(this: &Self) -> bool {
+        // 1 weak = the one held by the MyRc's
+        unsafe { strong(this.inner).get() == 1 && weak(this.inner).get() == 1 }
+    }
+}
+
+impl<T> MyRc<T> {
+    /// Get the value out if this is the only MyRc, otherwise get the MyRc back
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        if MyRc::strong_count(&this) != 1 {
+            return Err(this);
+        }
+
+        // we're taking care of the counts ourselves, so don't run MyRc's Drop
+        let this = ManuallyDrop::new(this);
+
+        unsafe {
+            // SAFETY: we're the only MyRc, so nothing else can read the value. Setting strong to 0
+            // makes sure that it's not read (or dropped) again through an upgraded MyWeak
+            let value = std::ptr::read(&*(*this.inner.as_ptr()).value);
+            strong(this.inner).set(0);
+
+            // give up the weak reference held by the MyRc's
+            drop(MyWeak {
+                inner: this.inner,
                 _marker: PhantomData,
+            });
+
+            Ok(value)
+        }
+    }
+
+    /// Same as try_unwrap, but drops the MyRc if it's not the last one
+    pub fn into_inner(this: Self) -> Option<T> {
+        MyRc::try_unwrap(this).ok()
+    }
+
+    /// Turn the MyRc into a pointer to the value, for example to pass it through C code as a
+    /// void*. The count is not decreased, so it has to be turned back with from_raw to not leak
+    pub fn into_raw(this: Self) -> *const T {
+        let this = ManuallyDrop::new(this);
+
+        unsafe { std::ptr::addr_of!((*this.inner.as_ptr()).value) as *const T }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from `MyRc::<T>::into_raw`, and every into_raw can be turned back with
+    /// from_raw only once
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // go back from the value to the start of the RcInner. repr(C) means the value always comes
+        // right after the counts (plus whatever padding T's alignment needs)
+        let (_, offset) = Layout::new::<RcInner<()>>()
+            .extend(Layout::new::<T>())
+            .unwrap();
+        let inner = (ptr as *const u8).sub(offset) as *mut RcInner<T>;
+
+        MyRc {
+            inner: NonNull::new_unchecked(inner),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Clone> MyRc<T> {
+    /// Clone on write. Gives mutable access to the value, cloning it into a new allocation first if
+    /// other MyRc's point to it
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        if MyRc::strong_count(this) != 1 {
+            // someone else can see the value, so make our own copy of it
+            *this = MyRc::new((**this).clone());
+        } else if MyRc::weak_count(this) != 0 {
+            // we're the only MyRc, but there are MyWeak's. No need to clone, we can move the value
+            // to a new allocation and leave the MyWeak's with one they can no longer upgrade
+            unsafe {
+                let value = std::ptr::read(&*(*this.inner.as_ptr()).value);
+                let fresh = MyRc::new(value);
+
+                strong(this.inner).set(0);
+                drop(MyWeak {
+                    inner: this.inner,
+                    _marker: PhantomData,
+                });
+
+                // overwrite without running Drop for the old MyRc, we've done that by hand above
+                std::ptr::write(this, fresh);
             }
         }
+
+        // SAFETY: we're now the only pointer to the value and we have a &mut to it
+        unsafe { &mut (*this.inner.as_ptr()).value }
     }
 }
 
-impl<T> Clone for MyRc<T> {
+impl<T: ?Sized> Clone for MyRc<T> {
     fn clone(&self) -> Self {
         // we're only increasing the reference count here and not cloning the actual data
 
-        unsafe {
-            let inner = self.inner.as_ref();
-            let c = inner.refcount.get();
-            inner.refcount.set(c + 1);
+        let strong = unsafe { strong(self.inner) };
+        strong.set(strong.get() + 1);
 
-            MyRc {
-                inner: self.inner,
-                _marker: PhantomData,
-            }
+        MyRc {
+            inner: self.inner,
+            _marker: PhantomData,
         }
     }
 }
 
-impl<T> std::ops::Deref for MyRc<T> {
+impl<T: ?Sized> std::ops::Deref for MyRc<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
+        // SAFETY: the value is alive for as long as there's a MyRc
         &unsafe { self.inner.as_ref() }.value
     }
 }
 
-impl<T> Drop for MyRc<T> {
+impl<T: ?Sized> Drop for MyRc<T> {
     fn drop(&mut self) {
         unsafe {
-            let inner = self.inner.as_ref();
-            let c = inner.refcount.get();
+            let strong = strong(self.inner);
+            let c = strong.get();
+            strong.set(c - 1);
 
             if c == 1 {
                 // SAFETY: We're the __only__ Rc left, and we are being dropped.
-                // thus, after us, there will be no Rc's and no references to T
-
-                drop(inner);
-                // _ = dropping the pointer
-                // Box::from_raw gives us a raw pointer
-                let _ = Box::from_raw(self.inner.as_ptr());
-            } else {
-                // SAFETY: There are other Rc's so don't drop the Box because other things are
-                // gonna need it
-                inner.refcount.set(c - 1);
+                // thus, after us, there will be no Rc's and no references to T.
+                // MyWeak's can still exist, but they see strong == 0 and won't give out a MyRc
+                ManuallyDrop::drop(&mut (*self.inner.as_ptr()).value);
+
+                // give up the weak reference held by the MyRc's. This frees the RcInner if there
+                // are no MyWeak's left
+                drop(MyWeak {
+                    inner: self.inner,
+                    _marker: PhantomData,
+                });
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> MyWeak<T> {
+    /// Get a MyRc to the value if it hasn't been dropped yet
+    pub fn upgrade(&self) -> Option<MyRc<T>> {
+        let strong = unsafe { strong(self.inner) };
+        let c = strong.get();
+
+        if c == 0 {
+            return None;
+        }
+
+        strong.set(c + 1);
+
+        Some(MyRc {
+            inner: self.inner,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn strong_count(&self) -> usize {
+        unsafe { strong(self.inner) }.get()
+    }
+
+    pub fn weak_count(&self) -> usize {
+        let weak = unsafe { weak(self.inner) }.get();
+
+        if self.strong_count() > 0 {
+            // don't count the weak reference held by the MyRc's
+            weak - 1
+        } else {
+            weak
+        }
+    }
+}
+
+impl<T: ?Sized> Clone for MyWeak<T> {
+    fn clone(&self) -> Self {
+        let weak = unsafe { weak(self.inner) };
+        weak.set(weak.get() + 1);
+
+        MyWeak {
+            inner: self.inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for MyWeak<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let weak = weak(self.inner);
+            let c = weak.get();
+            weak.set(c - 1);
+
+            if c == 1 {
+                // SAFETY: no MyRc's (they hold a weak reference together) and no MyWeak's left so
+                // nothing can access the RcInner anymore. The value has already been dropped by
+                // the last MyRc, so only free the memory
+                // for_value_raw as T might be unsized, in which case the size of the allocation
+                // is stored in the fat pointer (length of a slice, size in the vtable of a dyn)
+                #[cfg(feature = "rc-leak-detector")]
+                leak_detector::unregister(self.inner.as_ptr() as *const u8);
+
+                dealloc(
+                    self.inner.as_ptr() as *mut u8,
+                    Layout::for_value_raw(self.inner.as_ptr()),
+                );
+            }
+        }
+    }
+}
+
+// Comparing, hashing and printing a MyRc does the same thing as for the value inside it
+
+impl<T: ?Sized + PartialEq> PartialEq for MyRc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for MyRc<T> {}
+
+impl<T: ?Sized + PartialOrd> PartialOrd for MyRc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for MyRc<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: ?Sized + std::hash::Hash> std::hash::Hash for MyRc<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T: ?Sized + std::fmt::Debug> std::fmt::Debug for MyRc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized + std::fmt::Display> std::fmt::Display for MyRc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: Default> Default for MyRc<T> {
+    fn default() -> Self {
+        MyRc::new(T::default())
+    }
+}
+
+// lets a MyRc<String> turn into a MyRc<dyn Hello>, or a MyRc<[i32; 3]> into a MyRc<[i32]>, just
+// like a Box or a & would. Needs the nightly coerce_unsized and unsize features
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<MyRc<U>> for MyRc<T> {}
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<MyWeak<U>> for MyWeak<T> {}
+
+impl<T> MyRc<[T]> {
+    // Allocate an RcInner<[T]> with room for `len` elements. The counts are set to 1, but the
+    // elements themselves are left uninitialized for the caller to fill in
+    unsafe fn allocate_for_slice(len: usize) -> NonNull<RcInner<[T]>> {
+        // the counts, then the elements. As RcInner is repr(C) this is exactly the layout the
+        // compiler uses for RcInner<[T]>, which is what Layout::for_value_raw gives us back when
+        // the last MyWeak frees this
+        let layout = Layout::new::<RcInner<()>>()
+            .extend(Layout::array::<T>(len).expect("slice too large"))
+            .expect("slice too large")
+            .0
+            .pad_to_align();
+
+        let mem = alloc(layout);
+
+        if mem.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        // the metadata of a *mut [T] is its length. Casting it to *mut RcInner<[T]> keeps the
+        // length, so this is a fat pointer to an RcInner with `len` elements
+        let inner = std::ptr::slice_from_raw_parts_mut(mem as *mut T, len) as *mut RcInner<[T]>;
+
+        std::ptr::addr_of_mut!((*inner).strong).write(MyCell::new(1));
+        std::ptr::addr_of_mut!((*inner).weak).write(MyCell::new(1));
+
+        #[cfg(feature = "rc-leak-detector")]
+        leak_detector::register(mem, std::any::type_name::<[T]>());
+
+        NonNull::new_unchecked(inner)
+    }
+}
+
+impl<T> From<Vec<T>> for MyRc<[T]> {
+    fn from(mut v: Vec<T>) -> Self {
+        unsafe {
+            let inner = Self::allocate_for_slice(v.len());
+            let elements = std::ptr::addr_of_mut!((*inner.as_ptr()).value) as *mut T;
+
+            // move the elements over, then tell the Vec it's empty so that it only frees its
+            // buffer and doesn't drop the elements we've just moved out
+            std::ptr::copy_nonoverlapping(v.as_ptr(), elements, v.len());
+            v.set_len(0);
+
+            MyRc {
+                inner,
+                _marker: PhantomData,
             }
         }
     }
 }
 
+impl<T: Clone> From<&[T]> for MyRc<[T]> {
+    fn from(v: &[T]) -> Self {
+        MyRc::from(v.to_vec())
+    }
+}
+
+impl<T> FromIterator<T> for MyRc<[T]> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        // we need to know the length before allocating, so collect into a Vec first
+        MyRc::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl From<&str> for MyRc<str> {
+    fn from(s: &str) -> Self {
+        let bytes = ManuallyDrop::new(MyRc::<[u8]>::from(s.as_bytes()));
+
+        // SAFETY: str has the same layout as [u8], and the bytes came from a valid str
+        MyRc {
+            inner: unsafe { NonNull::new_unchecked(bytes.inner.as_ptr() as *mut RcInner<str>) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl From<String> for MyRc<str> {
+    fn from(s: String) -> Self {
+        MyRc::from(s.as_str())
+    }
+}
+
+// Hooks for the cycle collector in leak_detector. It only knows the address of an RcInner, so
+// these are instantiated for every traced T and handed to it as plain fn pointers
+
+#[cfg(feature = "rc-leak-detector")]
+impl<T: Trace> MyRc<T> {
+    /// Same as `new`, but the value can be found (and freed) by `leak_detector::collect_cycles`
+    pub fn new_traced(v: T) -> Self {
+        let rc = MyRc::new(v);
+
+        leak_detector::set_trace_ops(
+            rc.inner.as_ptr() as *const u8,
+            TraceOps {
+                trace: trace_value::<T>,
+                drop_value: drop_value::<T>,
+                release: release_value::<T>,
+            },
+        );
+
+        rc
+    }
+}
+
+#[cfg(feature = "rc-leak-detector")]
+impl<T: ?Sized> MyRc<T> {
+    pub(super) fn addr(this: &Self) -> *const u8 {
+        this.inner.as_ptr() as *const u8
+    }
+}
+
+/// # Safety
+///
+/// `addr` has to point to a live RcInner of any T. The counts are at the start of every RcInner
+/// (repr(C)), so they can be read without knowing what T is
+#[cfg(feature = "rc-leak-detector")]
+pub(super) unsafe fn counts_at<'a>(addr: *const u8) -> (&'a MyCell<usize>, &'a MyCell<usize>) {
+    let inner = addr as *const RcInner<()>;
+    (&(*inner).strong, &(*inner).weak)
+}
+
+#[cfg(feature = "rc-leak-detector")]
+unsafe fn trace_value<T: Trace>(addr: *const u8, tracer: &mut Tracer<'_>) {
+    (*(addr as *const RcInner<T>)).value.trace(tracer);
+}
+
+#[cfg(feature = "rc-leak-detector")]
+unsafe fn drop_value<T>(addr: *const u8) {
+    ManuallyDrop::drop(&mut (*(addr as *mut RcInner<T>)).value);
+}
+
+// the value has already been dropped by drop_value. Do what the last MyRc would have done
+// afterwards: mark it as dead and give up the weak reference held by the MyRc's
+#[cfg(feature = "rc-leak-detector")]
+unsafe fn release_value<T>(addr: *const u8) {
+    let inner = NonNull::new_unchecked(addr as *mut RcInner<T>);
+    strong(inner).set(0);
+
+    drop(MyWeak {
+        inner,
+        _marker: PhantomData,
+    });
+}
+
 pub fn tests() {}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::refcell::MyRefCell;
+    use super::*;
+    use crate::s9_dispatch_and_fat_pointers::dispatch_and_fat_pointers::Hello;
+
+    struct Node {
+        value: i32,
+        parent: Option<MyWeak<Node>>,
+        children: MyRefCell<Vec<MyRc<Node>>>,
+        drops: MyRc<MyCell<usize>>,
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn leaf(value: i32, parent: &MyWeak<Node>, drops: &MyRc<MyCell<usize>>) -> MyRc<Node> {
+        MyRc::new(Node {
+            value,
+            parent: Some(parent.clone()),
+            children: MyRefCell::new(vec![]),
+            drops: drops.clone(),
+        })
+    }
+
+    #[test]
+    fn weak_does_not_keep_value_alive() {
+        let rc = MyRc::new(String::from("hello"));
+        let weak = MyRc::downgrade(&rc);
+
+        assert_eq!(MyRc::strong_count(&rc), 1);
+        assert_eq!(MyRc::weak_count(&rc), 1);
+        assert_eq!(*weak.upgrade().unwrap(), "hello");
+
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(weak.strong_count(), 0);
+        assert_eq!(weak.weak_count(), 1);
+    }
+
+    #[test]
+    fn doubly_linked_tree_does_not_leak() {
+        let drops = MyRc::new(MyCell::new(0));
+
+        let root = MyRc::new_cyclic(|me| {
+            // the root can't be upgraded while it's being built
+            assert!(me.upgrade().is_none());
+
+            Node {
+                value: 0,
+                parent: None,
+                children: MyRefCell::new(vec![leaf(1, me, &drops), leaf(2, me, &drops)]),
+                drops: drops.clone(),
+            }
+        });
+
+        // grandchild added after the fact, pointing to its parent
+        let child = root.children.borrow()[0].clone();
+        let grandchild = leaf(3, &MyRc::downgrade(&child), &drops);
+        child.children.borrow_mut().push(grandchild.clone());
+
+        let parent = grandchild.parent.as_ref().unwrap().upgrade().unwrap();
+        assert_eq!(parent.value, 1);
+        let root_again = parent.parent.as_ref().unwrap().upgrade().unwrap();
+        assert_eq!(root_again.value, 0);
+        assert_eq!(MyRc::weak_count(&root), 2);
+
+        let weak_root = MyRc::downgrade(&root);
+        drop((parent, root_again, child, grandchild));
+        drop(root);
+
+        // every node has been dropped, even though the children point back to their parents
+        assert_eq!(drops.get(), 4);
+        assert_eq!(MyRc::strong_count(&drops), 1);
+        assert!(weak_root.upgrade().is_none());
+    }
+
+    #[test]
+    fn str_and_slices() {
+        let s: MyRc<str> = MyRc::from("hello");
+        let s2 = s.clone();
+        assert_eq!(&*s2, "hello");
+        assert_eq!(MyRc::strong_count(&s), 2);
+
+        let v: MyRc<[String]> = vec![String::from("a"), String::from("b")].into();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[1], "b");
+
+        let squares: MyRc<[u64]> = (1..=4).map(|i| i * i).collect();
+        assert_eq!(&*squares, &[1, 4, 9, 16]);
+
+        let empty: MyRc<[u8]> = MyRc::from(vec![]);
+        assert!(empty.is_empty());
+
+        // unsizing an array
+        let arr: MyRc<[u16]> = MyRc::new([1u16, 2, 3]);
+        assert_eq!(arr.len(), 3);
+    }
+
+    struct Loud(MyRc<MyCell<usize>>);
+
+    impl Hello for Loud {
+        fn hello(&self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    impl Drop for Loud {
+        fn drop(&mut self) {
+            self.0.set(100);
+        }
+    }
+
+    #[test]
+    fn trait_objects() {
+        let calls = MyRc::new(MyCell::new(0));
+
+        let h: MyRc<dyn Hello> = MyRc::new(Loud(calls.clone()));
+        let weak: MyWeak<dyn Hello> = MyRc::downgrade(&h);
+        h.hello();
+        weak.upgrade().unwrap().hello();
+        assert_eq!(calls.get(), 2);
+
+        // dropped through the vtable
+        drop(h);
+        assert_eq!(calls.get(), 100);
+        assert!(weak.upgrade().is_none());
+
+        let greetings: Vec<MyRc<dyn Hello>> = vec![MyRc::new("world"), MyRc::new(String::new())];
+        assert_eq!(greetings.len(), 2);
+    }
+
+    #[test]
+    fn get_mut_and_try_unwrap_need_a_unique_rc() {
+        let mut rc = MyRc::new(vec![1]);
+        MyRc::get_mut(&mut rc).unwrap().push(2);
+
+        let other = rc.clone();
+        assert!(MyRc::get_mut(&mut rc).is_none());
+        let rc = MyRc::try_unwrap(rc).unwrap_err();
+        drop(other);
+
+        let mut rc = rc;
+        let weak = MyRc::downgrade(&rc);
+        assert!(MyRc::get_mut(&mut rc).is_none());
+
+        assert_eq!(MyRc::try_unwrap(rc).unwrap(), vec![1, 2]);
+        assert!(weak.upgrade().is_none());
+
+        assert_eq!(MyRc::into_inner(MyRc::new(5)), Some(5));
+    }
+
+    #[test]
+    fn make_mut_clones_on_write() {
+        let mut a = MyRc::new(String::from("a"));
+        let b = a.clone();
+
+        MyRc::make_mut(&mut a).push('!');
+        assert_eq!(*a, "a!");
+        assert_eq!(*b, "a");
+        assert!(!MyRc::ptr_eq(&a, &b));
+
+        // the only MyRc, but with a MyWeak: the value moves and the MyWeak is disassociated
+        let weak = MyRc::downgrade(&a);
+        MyRc::make_mut(&mut a).push('?');
+        assert_eq!(*a, "a!?");
+        assert!(weak.upgrade().is_none());
+        assert_eq!(MyRc::weak_count(&a), 0);
+
+        let before = std::ptr::addr_of!(*a);
+        MyRc::make_mut(&mut a).push('.');
+        assert_eq!(std::ptr::addr_of!(*a), before);
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let rc = MyRc::new(42u8);
+        let other = rc.clone();
+
+        let ptr = MyRc::into_raw(rc);
+        assert_eq!(unsafe { *ptr }, 42);
+
+        let rc = unsafe { MyRc::from_raw(ptr) };
+        assert!(MyRc::ptr_eq(&rc, &other));
+        assert_eq!(MyRc::strong_count(&rc), 2);
+    }
+
+    #[test]
+    fn traits_forward_to_the_value() {
+        use std::collections::HashSet;
+
+        let mut v = vec![MyRc::new(3), MyRc::new(1), MyRc::new(2)];
+        v.sort();
+        assert_eq!(v, vec![MyRc::new(1), MyRc::new(2), MyRc::new(3)]);
+
+        let set: HashSet<MyRc<str>> = ["a", "b", "a"].into_iter().map(MyRc::from).collect();
+        assert_eq!(set.len(), 2);
+
+        assert_eq!(
+            format!("{:?} {}", MyRc::new("x"), MyRc::new(1.5)),
+            "\"x\" 1.5"
+        );
+        assert_eq!(*MyRc::<u32>::default(), 0);
+    }
+}
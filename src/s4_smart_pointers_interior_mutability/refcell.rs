@@ -1,4 +1,8 @@
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::Location;
+use std::ptr::NonNull;
 
 use super::cell::MyCell;
 
@@ -6,122 +10,504 @@ use super::cell::MyCell;
 enum RefState {
     Unshared,
     Shared(usize),
-    Exclusive,
+    // more than one MyRefMut can only exist after a map_split, and then they point to disjoint
+    // parts of the value
+    Exclusive(usize),
 }
 
 pub struct MyRefCell<T> {
     value: UnsafeCell<T>,
     state: MyCell<RefState>,
+    // where the exclusive borrow (or the most recent shared one) was taken. Only tracked in debug
+    // builds so that release builds don't pay for the extra Cell
+    #[cfg(debug_assertions)]
+    borrowed_at: MyCell<Option<&'static Location<'static>>>,
 }
 
+/// Returned by `try_borrow` when the cell is currently mutably borrowed
+pub struct BorrowError {
+    #[cfg(debug_assertions)]
+    location: Option<&'static Location<'static>>,
+}
+
+/// Returned by `try_borrow_mut` when the cell is currently borrowed (shared or mutably)
+pub struct BorrowMutError {
+    #[cfg(debug_assertions)]
+    location: Option<&'static Location<'static>>,
+    mutably: bool,
+}
+
+impl fmt::Debug for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("already mutably borrowed")?;
+
+        #[cfg(debug_assertions)]
+        if let Some(location) = self.location {
+            // Location's own Display adds the column as well, we only want file:line
+            write!(f, " at {}:{}", location.file(), location.line())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for BorrowError {}
+
+impl fmt::Debug for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.mutably {
+            f.write_str("already mutably borrowed")?;
+        } else {
+            f.write_str("already borrowed")?;
+        }
+
+        #[cfg(debug_assertions)]
+        if let Some(location) = self.location {
+            write!(f, " at {}:{}", location.file(), location.line())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for BorrowMutError {}
+
 impl<T> MyRefCell<T> {
     pub fn new(value: T) -> Self {
         Self {
             value: UnsafeCell::new(value),
             state: MyCell::new(RefState::Unshared),
+            #[cfg(debug_assertions)]
+            borrowed_at: MyCell::new(None),
         }
     }
 
-    pub fn borrow(&self) -> Option<MyRef<'_, T>> {
+    #[track_caller]
+    pub fn try_borrow(&self) -> Result<MyRef<'_, T>, BorrowError> {
         match self.state.get() {
             RefState::Unshared => {
                 self.state.set(RefState::Shared(1));
+                self.record_borrow();
                 // SAFETY: no exclusive references have been given out, since state would be
                 // exclusive
-                Some(MyRef { refcell: self })
+                Ok(self.make_ref())
             }
 
             RefState::Shared(n) => {
                 // SAFETY: no exclusive references have been given out, since state would be
                 // exclusive
                 self.state.set(RefState::Shared(n + 1));
-                Some(MyRef { refcell: self })
+                self.record_borrow();
+                Ok(self.make_ref())
             }
 
-            RefState::Exclusive => None,
+            RefState::Exclusive(_) => Err(BorrowError {
+                #[cfg(debug_assertions)]
+                location: self.borrowed_at.get(),
+            }),
         }
     }
 
-    pub fn borrow_mut(&self) -> Option<MyRefMut<'_, T>> {
-        if let RefState::Unshared = self.state.get() {
-            // SAFETY: no other state has been given out since sate would be Shared or
-            // Exclusive
-            self.state.set(RefState::Exclusive);
-            Some(MyRefMut { refcell: self })
-        } else {
-            None
+    #[track_caller]
+    pub fn try_borrow_mut(&self) -> Result<MyRefMut<'_, T>, BorrowMutError> {
+        match self.state.get() {
+            RefState::Unshared => {
+                // SAFETY: no other state has been given out since sate would be Shared or
+                // Exclusive
+                self.state.set(RefState::Exclusive(1));
+                self.record_borrow();
+                Ok(MyRefMut {
+                    // SAFETY: UnsafeCell never gives out a null pointer
+                    value: unsafe { NonNull::new_unchecked(self.value.get()) },
+                    borrow: BorrowRefMut { state: &self.state },
+                    _marker: PhantomData,
+                })
+            }
+
+            state => Err(BorrowMutError {
+                #[cfg(debug_assertions)]
+                location: self.borrowed_at.get(),
+                mutably: matches!(state, RefState::Exclusive(_)),
+            }),
         }
     }
+
+    /// Panicking version of `try_borrow`
+    #[track_caller]
+    pub fn borrow(&self) -> MyRef<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Panicking version of `try_borrow_mut`
+    #[track_caller]
+    pub fn borrow_mut(&self) -> MyRefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    // the state has to be Shared already when this is called
+    fn make_ref(&self) -> MyRef<'_, T> {
+        MyRef {
+            // SAFETY: UnsafeCell never gives out a null pointer
+            value: unsafe { NonNull::new_unchecked(self.value.get()) },
+            borrow: BorrowRef { state: &self.state },
+            _marker: PhantomData,
+        }
+    }
+
+    // #[track_caller] propagates, so this is the location of whoever called borrow / try_borrow
+    #[track_caller]
+    fn record_borrow(&self) {
+        #[cfg(debug_assertions)]
+        self.borrowed_at.set(Some(Location::caller()));
+    }
 }
 
-pub struct MyRef<'refcell, T> {
-    refcell: &'refcell MyRefCell<T>,
+// The guards only need to know about the state of the cell to release their borrow, not about the
+// T inside it. Splitting this out is what lets MyRef<U> / MyRefMut<U> point to a part of the T
+// (a field, an element) while still releasing the borrow on the whole cell when they're dropped
+struct BorrowRef<'refcell> {
+    state: &'refcell MyCell<RefState>,
 }
 
-impl<T> std::ops::Deref for MyRef<'_, T> {
-    type Target = T;
+impl BorrowRef<'_> {
+    fn clone(&self) -> Self {
+        match self.state.get() {
+            // SAFETY: we are a shared borrow ourselves, so no exclusive borrow can exist
+            RefState::Shared(n) => self.state.set(RefState::Shared(n + 1)),
+            RefState::Exclusive(_) | RefState::Unshared => unreachable!(),
+        }
 
-    fn deref(&self) -> &Self::Target {
-        // SAFETY: a Ref is only created if no exclusive references have been given out.
-        // once it is given out, state is set to shared so no exclusive references are given out
-        // so dereferencing into a shared reference is fine
-        unsafe { &*self.refcell.value.get() }
+        BorrowRef { state: self.state }
     }
 }
 
-impl<T> Drop for MyRef<'_, T> {
+impl Drop for BorrowRef<'_> {
     fn drop(&mut self) {
-        match self.refcell.state.get() {
-            RefState::Exclusive | RefState::Unshared => {
+        match self.state.get() {
+            RefState::Exclusive(_) | RefState::Unshared => {
                 unreachable!();
             }
             RefState::Shared(1) => {
-                self.refcell.state.set(RefState::Unshared);
+                self.state.set(RefState::Unshared);
             }
             RefState::Shared(n) => {
-                self.refcell.state.set(RefState::Shared(n - 1));
+                self.state.set(RefState::Shared(n - 1));
             }
         }
     }
 }
 
-pub struct MyRefMut<'refcell, T> {
-    refcell: &'refcell MyRefCell<T>,
+struct BorrowRefMut<'refcell> {
+    state: &'refcell MyCell<RefState>,
+}
+
+impl BorrowRefMut<'_> {
+    // only used by map_split. The caller has to make sure that the two guards point to disjoint
+    // parts of the value, the cell stays exclusively borrowed until both of them are dropped
+    fn split(&self) -> Self {
+        match self.state.get() {
+            RefState::Exclusive(n) => self.state.set(RefState::Exclusive(n + 1)),
+            RefState::Shared(_) | RefState::Unshared => unreachable!(),
+        }
+
+        BorrowRefMut { state: self.state }
+    }
+}
+
+impl Drop for BorrowRefMut<'_> {
+    fn drop(&mut self) {
+        match self.state.get() {
+            RefState::Shared(_) | RefState::Unshared => {
+                unreachable!();
+            }
+            RefState::Exclusive(1) => {
+                self.state.set(RefState::Unshared);
+            }
+            RefState::Exclusive(n) => {
+                self.state.set(RefState::Exclusive(n - 1));
+            }
+        }
+    }
+}
+
+pub struct MyRef<'refcell, T: ?Sized> {
+    // NonNull instead of a &'refcell T, as a reference would be assumed to be valid for the entire
+    // 'refcell, even after this MyRef has been dropped and a MyRefMut has been given out
+    value: NonNull<T>,
+    borrow: BorrowRef<'refcell>,
+    _marker: PhantomData<&'refcell T>,
+}
+
+impl<'refcell, T: ?Sized> MyRef<'refcell, T> {
+    /// Another shared borrow of the same cell. This is an associated function and not `Clone` so
+    /// that `r.clone()` still clones the T behind the MyRef
+    #[allow(clippy::should_implement_trait)]
+    pub fn clone(orig: &Self) -> Self {
+        MyRef {
+            value: orig.value,
+            borrow: orig.borrow.clone(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Make a MyRef to a part of the borrowed value, e.g. a field of a struct. The cell stays
+    /// borrowed until the returned MyRef is dropped
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MyRef<'refcell, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        MyRef {
+            value: NonNull::from(f(&*orig)),
+            borrow: orig.borrow,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Same as `map`, but the projection can fail. On failure we get the original MyRef back
+    pub fn filter_map<U: ?Sized, F>(orig: Self, f: F) -> Result<MyRef<'refcell, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(&*orig) {
+            Some(value) => Ok(MyRef {
+                value: NonNull::from(value),
+                borrow: orig.borrow,
+                _marker: PhantomData,
+            }),
+            None => Err(orig),
+        }
+    }
+}
+
+impl<T: ?Sized> std::ops::Deref for MyRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: a Ref is only created if no exclusive references have been given out.
+        // once it is given out, state is set to shared so no exclusive references are given out
+        // so dereferencing into a shared reference is fine
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MyRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+pub struct MyRefMut<'refcell, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: BorrowRefMut<'refcell>,
+    // &mut T and not &T, MyRefMut has to be invariant over T
+    _marker: PhantomData<&'refcell mut T>,
+}
+
+impl<'refcell, T: ?Sized> MyRefMut<'refcell, T> {
+    /// Make a MyRefMut to a part of the borrowed value. The cell stays exclusively borrowed until
+    /// the returned MyRefMut is dropped
+    pub fn map<U: ?Sized, F>(mut orig: Self, f: F) -> MyRefMut<'refcell, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        MyRefMut {
+            value: NonNull::from(f(&mut *orig)),
+            borrow: orig.borrow,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Split one exclusive borrow into two, e.g. two different fields of a struct or the two
+    /// halves of a slice from split_at_mut. The borrow checker already guarantees that the two
+    /// &mut returned by `f` don't overlap
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(
+        mut orig: Self,
+        f: F,
+    ) -> (MyRefMut<'refcell, U>, MyRefMut<'refcell, V>)
+    where
+        F: FnOnce(&mut T) -> (&mut U, &mut V),
+    {
+        let borrow = orig.borrow.split();
+        let (a, b) = f(&mut *orig);
+
+        (
+            MyRefMut {
+                value: NonNull::from(a),
+                borrow,
+                _marker: PhantomData,
+            },
+            MyRefMut {
+                value: NonNull::from(b),
+                borrow: orig.borrow,
+                _marker: PhantomData,
+            },
+        )
+    }
 }
 
-impl<T> std::ops::Deref for MyRefMut<'_, T> {
+impl<T: ?Sized> std::ops::Deref for MyRefMut<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
         // SAFETY:
         // see safety for DerefMut
-        unsafe { &*self.refcell.value.get() }
+        unsafe { self.value.as_ref() }
     }
 }
 
-impl<T> std::ops::DerefMut for MyRefMut<'_, T> {
+impl<T: ?Sized> std::ops::DerefMut for MyRefMut<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         // SAFETY: a RefMut is only created if no other references have been given out.
         // once it is given out, state is set to exclusive so no future references are given out
         // so we have an exclusive lease on the inner value so,
         // so mutably dereferencing into a shared reference is fine
-        unsafe { &mut *self.refcell.value.get() }
+        unsafe { self.value.as_mut() }
     }
 }
 
-impl<T> Drop for MyRefMut<'_, T> {
-    fn drop(&mut self) {
-        match self.refcell.state.get() {
-            RefState::Shared(_) | RefState::Unshared => {
-                unreachable!();
-            }
-            RefState::Exclusive => {
-                self.refcell.state.set(RefState::Unshared);
-            }
-        }
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MyRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
     }
 }
 
 pub fn tests() {
     println!("testing ref cell");
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn borrow_conflicts_are_errors() {
+        let c = MyRefCell::new(42);
+
+        let r1 = c.try_borrow().unwrap();
+        let r2 = c.try_borrow().unwrap();
+        assert!(c.try_borrow_mut().is_err());
+        drop(r1);
+        drop(r2);
+
+        let mut w = c.try_borrow_mut().unwrap();
+        *w += 1;
+        assert!(c.try_borrow().is_err());
+        assert!(c.try_borrow_mut().is_err());
+        drop(w);
+
+        assert_eq!(*c.borrow(), 43);
+    }
+
+    #[test]
+    fn error_points_at_the_conflicting_borrow() {
+        let c = MyRefCell::new(0);
+
+        let line = line!() + 1;
+        let w = c.borrow_mut();
+        let err = c.try_borrow().unwrap_err().to_string();
+
+        if cfg!(debug_assertions) {
+            assert_eq!(
+                err,
+                format!("already mutably borrowed at {}:{}", file!(), line)
+            );
+        } else {
+            assert_eq!(err, "already mutably borrowed");
+        }
+        drop(w);
+
+        let line = line!() + 1;
+        let r = c.borrow();
+        let err = c.try_borrow_mut().unwrap_err().to_string();
+
+        if cfg!(debug_assertions) {
+            assert_eq!(err, format!("already borrowed at {}:{}", file!(), line));
+        } else {
+            assert_eq!(err, "already borrowed");
+        }
+    }
+
+    fn is_unshared<T>(c: &MyRefCell<T>) -> bool {
+        matches!(c.state.get(), RefState::Unshared)
+    }
+
+    #[test]
+    fn map_and_clone_share_the_borrow() {
+        let c = MyRefCell::new((1, String::from("hello")));
+
+        let r = c.borrow();
+        let r2 = MyRef::clone(&r);
+        let s = MyRef::map(r, |(_, s)| s.as_str());
+        assert_eq!(&*s, "hello");
+        assert!(matches!(c.state.get(), RefState::Shared(2)));
+        assert!(c.try_borrow_mut().is_err());
+
+        drop(r2);
+        assert!(c.try_borrow_mut().is_err());
+        drop(s);
+        assert!(is_unshared(&c));
+    }
+
+    #[test]
+    fn filter_map_gives_back_the_original() {
+        let c = MyRefCell::new(vec![1, 2, 3]);
+
+        let r = MyRef::filter_map(c.borrow(), |v| v.get(10)).unwrap_err();
+        assert_eq!(*r, vec![1, 2, 3]);
+
+        let r = MyRef::filter_map(r, |v| v.get(1)).unwrap();
+        assert_eq!(*r, 2);
+        assert!(c.try_borrow_mut().is_err());
+
+        drop(r);
+        assert!(is_unshared(&c));
+    }
+
+    #[test]
+    fn map_mut_and_split() {
+        let c = MyRefCell::new([1, 2, 3, 4]);
+
+        let mut first = MyRefMut::map(c.borrow_mut(), |a| &mut a[0]);
+        *first = 10;
+        drop(first);
+        assert!(is_unshared(&c));
+
+        let (mut left, mut right) = MyRefMut::map_split(c.borrow_mut(), |a| a.split_at_mut(2));
+        left[1] = 20;
+        right[0] = 30;
+        assert!(matches!(c.state.get(), RefState::Exclusive(2)));
+
+        drop(left);
+        assert!(c.try_borrow().is_err());
+        drop(right);
+        assert!(is_unshared(&c));
+
+        assert_eq!(*c.borrow(), [10, 20, 30, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn borrow_panics_on_conflict() {
+        let c = MyRefCell::new(0);
+        let _w = c.borrow_mut();
+        let _r = c.borrow();
+    }
+}
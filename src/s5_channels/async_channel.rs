@@ -0,0 +1,404 @@
+// The same channel again, for async code. receive in channels.rs waits on a Condvar, which blocks
+// the whole thread, and with it every other task the runtime would have run on that thread. Here
+// recv and send return futures instead. When they can't go ahead they leave their Waker in Shared
+// and return Pending, and whoever changes the state later (a send, a receive making room, one of
+// the ends going away) wakes them, the same places channels.rs notifies its condvars.
+//
+// Cancellation: a future can be dropped at any point it returned Pending, e.g. when it loses a
+// select or hits a timeout. Recv only takes an item out of the queue in the same poll that returns
+// it, so dropping it never loses anything. There's also no batching into a buffer like in
+// channels.rs, the queue is the only place items ever are. A dropped SendFuture drops the item it
+// was trying to send, it was never in the channel.
+
+use super::channels::{SendError, TryRecvError, TrySendError};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+struct Inner<T> {
+    queue: VecDeque<T>,
+    // None for an unbounded channel
+    capacity: Option<usize>,
+    senders: usize,
+    receiver_alive: bool,
+    // the pending recv, if there is one
+    recv_waker: Option<Waker>,
+    // every pending send on a full channel, keyed by an id so that a dropped SendFuture can take
+    // its waker back out
+    send_wakers: Vec<(u64, Waker)>,
+    next_send_id: u64,
+}
+
+impl<T> Inner<T> {
+    fn wake_receiver(&mut self) {
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // wake them all, like space.notify_all in channels.rs. Waking just one wouldn't do: it could
+    // belong to a future that's about to be dropped, and the wakeup would be lost
+    fn wake_senders(&mut self) {
+        for (_, waker) in self.send_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        matches!(self.capacity, Some(capacity) if self.queue.len() >= capacity)
+    }
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+pub struct Sender<T> {
+    shared: MyArc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: MyArc<Shared<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.senders -= 1;
+
+        if inner.senders == 0 {
+            inner.wake_receiver();
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Resolves once the item is in the channel, which for a bounded channel means waiting for
+    /// room. Fails if the receiver is gone, handing the item back
+    pub fn send(&mut self, t: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            item: Some(t),
+            waker_id: None,
+        }
+    }
+
+    pub fn try_send(&mut self, t: T) -> Result<(), TrySendError<T>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        if !inner.receiver_alive {
+            return Err(TrySendError::Disconnected(t));
+        }
+
+        if inner.is_full() {
+            return Err(TrySendError::Full(t));
+        }
+
+        inner.queue.push_back(t);
+        inner.wake_receiver();
+        Ok(())
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    sender: &'a mut Sender<T>,
+    // taken out once it's sent
+    item: Option<T>,
+    // set while our waker is in send_wakers
+    waker_id: Option<u64>,
+}
+
+// nothing in SendFuture is structurally pinned, it can be moved around even after it's been polled
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut inner = this.sender.shared.inner.lock().unwrap();
+        let t = this
+            .item
+            .take()
+            .expect("SendFuture polled after it completed");
+
+        if !inner.receiver_alive {
+            this.waker_id = None;
+            return Poll::Ready(Err(SendError(t)));
+        }
+
+        if !inner.is_full() {
+            inner.queue.push_back(t);
+            inner.wake_receiver();
+            // wake_senders drained our waker when it woke us, and if it didn't we take it out
+            // ourselves, it's of no use anymore
+            if let Some(id) = this.waker_id.take() {
+                inner.send_wakers.retain(|(other, _)| *other != id);
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        this.item = Some(t);
+
+        // the channel is full: (re)register. We might have been woken for a slot someone else got
+        // to first, in which case our old entry is gone
+        let id = match this.waker_id {
+            Some(id) => id,
+            None => {
+                let id = inner.next_send_id;
+                inner.next_send_id += 1;
+                this.waker_id = Some(id);
+                id
+            }
+        };
+
+        match inner.send_wakers.iter_mut().find(|(other, _)| *other == id) {
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None => inner.send_wakers.push((id, cx.waker().clone())),
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waker_id {
+            self.sender
+                .shared
+                .inner
+                .lock()
+                .unwrap()
+                .send_wakers
+                .retain(|(other, _)| *other != id);
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Resolves to the next item, or None once all the senders are gone and the channel is empty
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        match inner.queue.pop_front() {
+            Some(t) => {
+                inner.wake_senders();
+                Ok(t)
+            }
+            None if inner.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.receiver.shared.inner.lock().unwrap();
+
+        if let Some(t) = inner.queue.pop_front() {
+            inner.wake_senders();
+            return Poll::Ready(Some(t));
+        }
+
+        if inner.senders == 0 {
+            return Poll::Ready(None);
+        }
+
+        // there's only one receiver, and recv borrows it mutably, so there's only ever one of
+        // these pending. Checked and registered under the same lock, so a send can't slip in
+        // between and wake nobody
+        match &mut inner.recv_waker {
+            Some(waker) => waker.clone_from(cx.waker()),
+            None => inner.recv_waker = Some(cx.waker().clone()),
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.receiver_alive = false;
+        inner.recv_waker = None;
+        inner.wake_senders();
+
+        let queued = std::mem::take(&mut inner.queue);
+        drop(inner);
+
+        // outside the lock, same as channels.rs
+        drop(queued);
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None)
+}
+
+/// Bounded: send waits while there are `capacity` items in the channel. There's no rendezvous
+/// flavour, a capacity of 0 means send never goes through
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "async channels need a capacity of at least 1");
+    new_channel(Some(capacity))
+}
+
+fn new_channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = MyArc::new(Shared {
+        inner: Mutex::new(Inner {
+            queue: VecDeque::new(),
+            capacity,
+            senders: 1,
+            receiver_alive: true,
+            recv_waker: None,
+            send_wakers: Vec::new(),
+            next_send_id: 0,
+        }),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub fn tests() {
+    use super::executor::block_on;
+
+    println!("Testing async channel");
+
+    let (mut tx, mut rx) = channel();
+    block_on(async {
+        tx.send(42).await.unwrap();
+        assert_eq!(rx.recv().await, Some(42));
+    });
+
+    drop(tx);
+    assert_eq!(block_on(rx.recv()), None);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::executor::{block_on, Executor};
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    #[test]
+    fn tasks_on_one_thread_take_turns() {
+        // capacity 1: the producer has to wait for the consumer after every item, so the two tasks
+        // go back and forth on the one thread
+        let (mut tx, mut rx) = sync_channel(1);
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let mut executor = Executor::new();
+        let producer_log = log.clone();
+        executor.spawn(async move {
+            for i in 0..3 {
+                tx.send(i).await.unwrap();
+                producer_log.borrow_mut().push(format!("sent {}", i));
+            }
+        });
+        let consumer_log = log.clone();
+        executor.spawn(async move {
+            while let Some(i) = rx.recv().await {
+                consumer_log.borrow_mut().push(format!("got {}", i));
+            }
+        });
+        executor.run();
+
+        assert_eq!(
+            *log.borrow(),
+            ["sent 0", "got 0", "sent 1", "got 1", "sent 2", "got 2"]
+        );
+    }
+
+    #[test]
+    fn woken_from_another_thread() {
+        let (mut tx, mut rx) = channel();
+
+        let sender = spawn(move || {
+            sleep(Duration::from_millis(50));
+            block_on(tx.send(String::from("hi"))).unwrap();
+        });
+
+        assert_eq!(block_on(rx.recv()).as_deref(), Some("hi"));
+        assert_eq!(block_on(rx.recv()), None);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn dropping_a_pending_recv_loses_nothing() {
+        let (mut tx, mut rx) = channel();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // starts waiting, gets woken up by the send, and is cancelled before it's polled again
+        {
+            let mut recv = rx.recv();
+            assert!(Pin::new(&mut recv).poll(&mut cx).is_pending());
+            block_on(tx.send(1)).unwrap();
+        }
+
+        assert_eq!(block_on(rx.recv()), Some(1));
+    }
+
+    #[test]
+    fn dropped_send_does_not_block_the_others() {
+        let (mut tx1, mut rx) = sync_channel(1);
+        let mut tx2 = tx1.clone();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        block_on(tx1.send(0)).unwrap();
+
+        // both wait for room, the first one gives up
+        let mut first = tx1.send(1);
+        assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+
+        let second = spawn(move || block_on(tx2.send(2)));
+        sleep(Duration::from_millis(50));
+        drop(first);
+
+        assert_eq!(block_on(rx.recv()), Some(0));
+        second.join().unwrap().unwrap();
+        assert_eq!(block_on(rx.recv()), Some(2));
+    }
+
+    #[test]
+    fn send_fails_once_the_receiver_is_gone() {
+        let (mut tx, rx) = sync_channel(1);
+        block_on(tx.send(1)).unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut send = tx.send(2);
+        assert!(Pin::new(&mut send).poll(&mut cx).is_pending());
+
+        drop(rx);
+        assert_eq!(block_on(send), Err(SendError(2)));
+    }
+}
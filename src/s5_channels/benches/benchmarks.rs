@@ -0,0 +1,125 @@
+use super::super::channels;
+use super::super::lockfree;
+use super::super::spsc;
+
+use std::hint::spin_loop;
+use std::thread::spawn;
+use std::time::Instant;
+
+// Every producer sends the same number of messages as fast as it can, the receiver takes them all.
+// With more producers, the mutex channel spends more and more of its time fighting over the lock,
+// the lock free one only ever has them race on the one atomic swap. With just the one producer
+// there's the spsc ring as well, which doesn't even have that
+pub fn run_benchmarks() {
+    const MESSAGES: usize = 1_000_000;
+
+    println!("backend producers messages time");
+    for &producers in &[1, 2, 4, 8, 16] {
+        let per_producer = MESSAGES / producers;
+
+        for _ in 0..5 {
+            let took = bench_mutex(producers, per_producer);
+            println!("mutex {} {} {}", producers, MESSAGES, took);
+            let took = bench_lockfree(producers, per_producer);
+            println!("lockfree {} {} {}", producers, MESSAGES, took);
+            if producers == 1 {
+                let took = bench_spsc(MESSAGES);
+                println!("spsc {} {} {}", producers, MESSAGES, took);
+            }
+        }
+    }
+}
+
+pub fn bench_mutex(producers: usize, per_producer: usize) -> f64 {
+    let (tx, mut rx) = channels::channel();
+
+    let time = Instant::now();
+    let handles: Vec<_> = (0..producers)
+        .map(|_| {
+            let mut tx = tx.clone();
+            spawn(move || {
+                for i in 0..per_producer {
+                    tx.send(i).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let received = rx.iter().count();
+    let took = time.elapsed();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(received, producers * per_producer);
+
+    took.as_secs_f64()
+}
+
+pub fn bench_lockfree(producers: usize, per_producer: usize) -> f64 {
+    let (tx, mut rx) = lockfree::channel();
+
+    let time = Instant::now();
+    let handles: Vec<_> = (0..producers)
+        .map(|_| {
+            let mut tx = tx.clone();
+            spawn(move || {
+                for i in 0..per_producer {
+                    tx.send(i).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let received = rx.iter().count();
+    let took = time.elapsed();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(received, producers * per_producer);
+
+    took.as_secs_f64()
+}
+
+// Publishes and releases every 64 items, or when it has to: the producer when the ring is full,
+// the consumer when it's empty
+pub fn bench_spsc(messages: usize) -> f64 {
+    let (mut tx, mut rx) = spsc::ring(1024);
+
+    let time = Instant::now();
+    let handle = spawn(move || {
+        for i in 0..messages {
+            while tx.push(i).is_err() {
+                tx.publish();
+                spin_loop();
+            }
+            if i % 64 == 63 {
+                tx.publish();
+            }
+        }
+    });
+
+    let mut received = 0;
+    while received < messages {
+        match rx.pop() {
+            Some(_) => {
+                received += 1;
+                if received % 64 == 0 {
+                    rx.release();
+                }
+            }
+            None => {
+                rx.release();
+                spin_loop();
+            }
+        }
+    }
+    let took = time.elapsed();
+
+    handle.join().unwrap();
+
+    took.as_secs_f64()
+}
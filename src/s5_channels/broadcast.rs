@@ -0,0 +1,330 @@
+// Every receiver gets every message. For things like config reloads or a shutdown signal, where
+// it's not about handing out work but about telling everyone.
+//
+// The messages go into a ring buffer of `capacity` slots, and each receiver keeps track of the
+// sequence number of the next message it wants to read. Sending never waits for the receivers:
+// once the buffer is full the oldest message is overwritten. A receiver that was still going to
+// read it has fallen too far behind, and gets a Lagged error with the number of messages it missed,
+// after which it carries on from the oldest one that's still there.
+//
+//   seq:      5   6   7   8          tail = 9 (next one to be written)
+//   slots:  [ 8 | 5 | 6 | 7 ]        capacity 4, message n lives in slot n % 4
+//
+// A receiver at 6 reads 6, 7, 8. A receiver at 3 has lost 3 and 4, it gets Lagged(2) and then 5.
+//
+// Receivers get a clone of every message, so T: Clone.
+
+use super::channels::{SendError, Shared};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+
+struct State<T> {
+    slots: Vec<Option<T>>,
+    // sequence number of the next message to be sent
+    tail: u64,
+    senders: usize,
+    receivers: usize,
+}
+
+impl<T> State<T> {
+    fn capacity(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    // the oldest message that hasn't been overwritten yet
+    fn head(&self) -> u64 {
+        self.tail.saturating_sub(self.capacity())
+    }
+}
+
+pub struct Sender<T> {
+    shared: MyArc<Shared<State<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: MyArc<Shared<State<T>>>,
+    // sequence number of the next message we'll read
+    next: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecvError {
+    /// We fell behind and this many messages were overwritten before we got to them. The next
+    /// receive gets the oldest message that's still there
+    Lagged(u64),
+    /// All the senders are gone and we've seen everything they sent
+    Closed,
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            RecvError::Closed => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            TryRecvError::Closed => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+impl<T> Sender<T> {
+    /// Never blocks. Returns how many receivers there are to see the message, fails if there are
+    /// none
+    pub fn send(&self, t: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.inner.lock().unwrap();
+
+        if state.receivers == 0 {
+            return Err(SendError(t));
+        }
+
+        let slot = (state.tail % state.capacity()) as usize;
+        // whatever was in there is the oldest message, and it's gone now
+        let oldest = state.slots[slot].replace(t);
+        state.tail += 1;
+        let receivers = state.receivers;
+        drop(state);
+
+        // outside the lock, same as in watch
+        drop(oldest);
+        self.shared.available.notify_all();
+        Ok(receivers)
+    }
+
+    /// A new receiver that only sees messages sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.inner.lock().unwrap();
+        state.receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.inner.lock().unwrap().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.inner.lock().unwrap();
+        state.senders -= 1;
+        let was_last = state.senders == 0;
+        drop(state);
+
+        if was_last {
+            // every receiver needs to hear about this one
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+impl<T: Clone> State<T> {
+    // the message at `next` for a receiver, moving it on. None if there's nothing new yet
+    fn read(&self, next: &mut u64) -> Option<Result<T, RecvError>> {
+        let head = self.head();
+
+        if *next < head {
+            let missed = head - *next;
+            *next = head;
+            return Some(Err(RecvError::Lagged(missed)));
+        }
+
+        if *next < self.tail {
+            let slot = (*next % self.capacity()) as usize;
+            *next += 1;
+            let t = self.slots[slot]
+                .clone()
+                .expect("slot between head and tail is empty");
+            return Some(Ok(t));
+        }
+
+        if self.senders == 0 {
+            return Some(Err(RecvError::Closed));
+        }
+
+        None
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn receive(&mut self) -> Result<T, RecvError> {
+        let mut state = self.shared.inner.lock().unwrap();
+
+        loop {
+            if let Some(result) = state.read(&mut self.next) {
+                return result;
+            }
+
+            state = self.shared.available.wait(state).unwrap();
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.inner.lock().unwrap();
+
+        match state.read(&mut self.next) {
+            Some(Ok(t)) => Ok(t),
+            Some(Err(RecvError::Lagged(n))) => Err(TryRecvError::Lagged(n)),
+            Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone starts where this one is, so it sees the same messages from here on
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().receivers -= 1;
+    }
+}
+
+/// A channel where every receiver sees every message, as long as it keeps up. At most `capacity`
+/// messages are kept around for the receivers that are behind
+pub fn broadcast<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        capacity > 0,
+        "broadcast channels need a capacity of at least 1"
+    );
+
+    let state = State {
+        slots: (0..capacity).map(|_| None).collect(),
+        tail: 0,
+        senders: 1,
+        receivers: 1,
+    };
+    let shared = MyArc::new(Shared::new(state));
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+pub fn tests() {
+    println!("Testing broadcast");
+
+    let (tx, mut rx1) = broadcast(4);
+    let mut rx2 = tx.subscribe();
+
+    assert_eq!(tx.send("reload"), Ok(2));
+    assert_eq!(rx1.receive(), Ok("reload"));
+    assert_eq!(rx2.receive(), Ok("reload"));
+
+    drop(tx);
+    assert_eq!(rx1.receive(), Err(RecvError::Closed));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::thread::spawn;
+
+    #[test]
+    fn every_receiver_sees_every_message() {
+        let (tx, rx) = broadcast(16);
+
+        let receivers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut rx = rx.clone();
+                spawn(move || {
+                    let mut got = vec![];
+                    while let Ok(i) = rx.receive() {
+                        got.push(i);
+                    }
+                    got
+                })
+            })
+            .collect();
+        drop(rx);
+
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        for receiver in receivers {
+            assert_eq!(receiver.join().unwrap(), (0..10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn slow_receiver_lags_instead_of_blocking_the_sender() {
+        let (tx, mut slow) = broadcast(4);
+        let mut fast = tx.subscribe();
+
+        for i in 0..10 {
+            tx.send(i).unwrap();
+            assert_eq!(fast.try_recv(), Ok(i));
+        }
+
+        // 0 to 5 have been overwritten, 6 to 9 are still there
+        assert_eq!(slow.try_recv(), Err(TryRecvError::Lagged(6)));
+        assert_eq!(slow.try_recv(), Ok(6));
+        assert_eq!(slow.receive(), Ok(7));
+
+        drop(tx);
+        assert_eq!(slow.receive(), Ok(8));
+        assert_eq!(slow.receive(), Ok(9));
+        assert_eq!(slow.receive(), Err(RecvError::Closed));
+        assert_eq!(fast.try_recv(), Err(TryRecvError::Closed));
+    }
+
+    #[test]
+    fn subscribers_only_see_new_messages() {
+        let (tx, rx) = broadcast(4);
+        tx.send(1).unwrap();
+
+        let mut late = tx.subscribe();
+        assert_eq!(late.try_recv(), Err(TryRecvError::Empty));
+        tx.send(2).unwrap();
+        assert_eq!(late.try_recv(), Ok(2));
+
+        drop(rx);
+        drop(late);
+        assert_eq!(tx.receiver_count(), 0);
+        assert_eq!(tx.send(3), Err(SendError(3)));
+    }
+}
@@ -10,19 +10,36 @@
 //  synchronization
 //
 //  - Oneshot channels: Channles you only send at once. Any capacity, in practice only 1 call to
-//  send()
+//  send(). See oneshot.rs
+//
+// The Receiver here is the only one, see mpmc for the flavour where it can be cloned. Both use the
+// same Shared and the same Sender
+//
+// A bounded channel doesn't have to block when it's full. bounded_channel takes an OverflowPolicy,
+// and with anything other than Block a send on a full channel always goes through right away, by
+// dropping the oldest item, dropping the one being sent, or merging it into the newest one. That's
+// for things like telemetry, where a producer stalling is worse than losing a few samples. Every
+// Sender counts how many of its sends were dropped or merged
+//
+// Any channel can be instrumented, see stats.rs
 
-use std::collections::VecDeque;
-use std::sync::{Arc, Condvar, Mutex}; // VecDeque = kinda like a ring buffer
-                                      // Arc = Atomically reference counted type
+use super::select::{Selectable, Signal};
+use super::stats::{ChannelStats, Registration};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc; // MyArc = Atomically reference counted type
+use std::collections::VecDeque; // VecDeque = kinda like a ring buffer
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 pub struct Sender<T> {
-    shared: Arc<Shared<T>>,
+    shared: MyArc<Shared<Inner<T>>>,
+    // what the overflow policy did to this sender's sends, see dropped() and merged()
+    dropped: u64,
+    merged: u64,
 }
 
 pub struct Receiver<T> {
-    shared: Arc<Shared<T>>,
-    // since we have only one receiver, we don't need to take the lock on every receive
+    shared: MyArc<Shared<Inner<T>>>,
+    // items we took out of the queue in one go, so we don't need to take the lock on every receive
     buffer: VecDeque<T>,
 }
 
@@ -30,14 +47,231 @@ struct Inner<T> {
     // things in the channel
     queue: VecDeque<T>,
     senders: usize,
+    // None for an unbounded channel. Some(0) is a rendezvous channel
+    capacity: Option<usize>,
+    // what a send does when the channel is full. Always Block for a rendezvous channel
+    overflow: OverflowPolicy<T>,
+    // how many items the receiver has taken out of the queue. Only kept track of for bounded
+    // channels, a rendezvous sender waits for this to go past its item
+    taken: u64,
+    // how many receivers are blocked in receive. A rendezvous try_send can only hand its item over
+    // if someone is already there to take it
+    receivers_waiting: usize,
+    // once the receivers are all gone nothing will ever take items out again, so senders should
+    // stop putting them in
+    receivers: usize,
+    // every Select that's blocked on this channel. Anything that could make a send or a receive
+    // ready fires all of them, and they go and check for themselves
+    selectors: Vec<MyArc<Signal>>,
+    // Some once the channel is instrumented
+    stats: Option<Registration>,
+}
+
+/// What a send on a full bounded channel does
+pub enum OverflowPolicy<T> {
+    /// Wait for room, like sync_channel
+    Block,
+    /// Make room by dropping the item that's been in the channel the longest
+    DropOldest,
+    /// Drop the item being sent. The send still succeeds
+    DropNewest,
+    /// Merge the item being sent into the newest one in the channel, e.g. add up counters or keep
+    /// the max
+    Coalesce(fn(&mut T, T)),
+}
+
+// by hand, derive would want T: Clone, and all there is of T in here is a fn pointer
+impl<T> Clone for OverflowPolicy<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for OverflowPolicy<T> {}
+
+impl<T> std::fmt::Debug for OverflowPolicy<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverflowPolicy::Block => f.write_str("Block"),
+            OverflowPolicy::DropOldest => f.write_str("DropOldest"),
+            OverflowPolicy::DropNewest => f.write_str("DropNewest"),
+            OverflowPolicy::Coalesce(_) => f.write_str("Coalesce(..)"),
+        }
+    }
+}
+
+// What happened to a send on a full channel that doesn't block
+enum Overflowed<T> {
+    // either the oldest item or the one being sent, to be dropped once the lock is given up
+    Dropped(T),
+    Merged,
+}
+
+impl<T> Inner<T> {
+    // when the channel is instrumented, the time a wait starts
+    fn wait_start(&self) -> Option<Instant> {
+        self.stats.as_ref().map(|_| Instant::now())
+    }
+
+    fn send_waited(&self, since: Option<Instant>) {
+        if let (Some(stats), Some(since)) = (&self.stats, since) {
+            stats.send_waited(since);
+        }
+    }
+
+    fn recv_waited(&self, since: Option<Instant>) {
+        if let (Some(stats), Some(since)) = (&self.stats, since) {
+            stats.recv_waited(since);
+        }
+    }
+
+    // an item went into the queue
+    fn count_sent(&self) {
+        if let Some(stats) = &self.stats {
+            stats.sent(self.queue.len());
+        }
+    }
+
+    // a send that overflowed, see overflow()
+    fn count_overflow(&self, overflowed: &Overflowed<T>) {
+        if let Some(stats) = &self.stats {
+            stats.sent(self.queue.len());
+            match overflowed {
+                Overflowed::Dropped(_) => stats.dropped(),
+                Overflowed::Merged => stats.merged(),
+            }
+        }
+    }
+
+    // n items came out of the queue
+    fn count_received(&self, n: usize) {
+        if let Some(stats) = &self.stats {
+            stats.received(n, self.queue.len());
+        }
+    }
+
+    fn instrument(&mut self, name: &str) {
+        self.stats = Some(Registration::new(name.to_string(), self.queue.len()));
+    }
+
+    fn stats(&self) -> Option<ChannelStats> {
+        self.stats.as_ref().map(|stats| stats.snapshot())
+    }
+
+    // called with the lock held. Signal has its own lock, and Select never takes ours while
+    // holding that one, so this can't deadlock
+    fn wake_selectors(&self) {
+        for signal in &self.selectors {
+            signal.fire();
+        }
+    }
 }
 
-struct Shared<T> {
+// The lock and the two condvars every channel is built around. S is whatever state the channel
+// keeps behind the lock, Inner here, and the broadcast and watch channels bring their own
+pub(super) struct Shared<S> {
     // things in the channel
-    inner: Mutex<Inner<T>>,
-    available: Condvar,
+    pub(super) inner: Mutex<S>,
+    // receivers wait on this for something to receive
+    pub(super) available: Condvar,
+    // senders of a bounded channel wait on this for the queue to have room again
+    pub(super) space: Condvar,
 }
 
+impl<S> Shared<S> {
+    pub(super) fn new(inner: S) -> Self {
+        Shared {
+            inner: Mutex::new(inner),
+            available: Condvar::new(),
+            space: Condvar::new(),
+        }
+    }
+}
+
+/// Returned by `send` when the receiver has been dropped. Hands the item back
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+// no T: Debug bound, so that .unwrap() works for any T
+impl<T> std::fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("sending on a closed channel")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// Returned by `try_send` when the item couldn't be sent right away
+pub enum TrySendError<T> {
+    /// The channel is bounded and full (or, for a rendezvous channel, nobody is waiting to take
+    /// the item). Hands the item back
+    Full(T),
+    /// The receiver has been dropped. Hands the item back
+    Disconnected(T),
+}
+
+impl<T> std::fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T> std::error::Error for TrySendError<T> {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+    /// Nothing in the channel right now, but there are still senders
+    Empty,
+    /// Nothing in the channel, and all the senders are gone
+    Disconnected,
+}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
+impl std::fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl std::error::Error for RecvTimeoutError {}
+
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
         let mut inner = self.shared.inner.lock().unwrap();
@@ -47,9 +281,11 @@ impl<T> Clone for Sender<T> {
         drop(inner);
 
         Self {
-            // to tell Rust to clone the Arc, i.e. the reference counter instead of the shared thing
-            // that Arc holds which is the VecDeque
-            shared: Arc::clone(&self.shared),
+            // to tell Rust to clone the MyArc, i.e. the reference counter instead of the shared
+            // thing that MyArc holds which is the VecDeque
+            shared: MyArc::clone(&self.shared),
+            dropped: 0,
+            merged: 0,
         }
     }
 }
@@ -59,25 +295,171 @@ impl<T> Drop for Sender<T> {
         let mut inner = self.shared.inner.lock().unwrap();
         inner.senders -= 1;
         let was_last = inner.senders == 0;
+        if was_last {
+            inner.wake_selectors();
+        }
         drop(inner);
 
         if was_last {
-            // last sender drop. Wake the receiver
-            self.shared.available.notify_one();
+            // last sender drop. Wake every receiver, they all need to see it
+            self.shared.available.notify_all();
         }
     }
 }
 
 impl<T> Sender<T> {
-    pub fn send(&mut self, t: T) {
+    /// Fails if the receiver has been dropped, handing the item back. If the receiver is dropped
+    /// while we're blocked on a full channel, that's an error as well
+    pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
         let mut inner = self.shared.inner.lock().unwrap();
-        // it might be that the VecDeque's size increases which means that send would take longer
-        // and not necessarily that send will block
+
+        match inner.capacity {
+            // it might be that the VecDeque's size increases which means that send would take
+            // longer and not necessarily that send will block
+            None => {}
+            // rendezvous: only one item is handed over at a time, wait for the one before ours
+            Some(0) => {
+                while inner.receivers > 0 && !inner.queue.is_empty() {
+                    let since = inner.wait_start();
+                    inner = self.shared.space.wait(inner).unwrap();
+                    inner.send_waited(since);
+                }
+            }
+            // backpressure: wait for the receiver to make room
+            Some(capacity) if matches!(inner.overflow, OverflowPolicy::Block) => {
+                while inner.receivers > 0 && inner.queue.len() >= capacity {
+                    let since = inner.wait_start();
+                    inner = self.shared.space.wait(inner).unwrap();
+                    inner.send_waited(since);
+                }
+            }
+            // the overflow policy takes care of a full channel, below
+            Some(_) => {}
+        }
+
+        if inner.receivers == 0 {
+            return Err(SendError(t));
+        }
+
+        if inner.overflows() {
+            let overflowed = inner.overflow(t);
+            drop(inner);
+            self.count(overflowed);
+            return Ok(());
+        }
+
         inner.queue.push_back(t);
+        inner.count_sent();
+        inner.wake_selectors();
+
+        if inner.capacity == Some(0) {
+            // the queue was empty, so ours is the next item the receiver takes
+            let ticket = inner.taken + 1;
+            self.shared.available.notify_one();
+
+            while inner.receivers > 0 && inner.taken < ticket {
+                let since = inner.wait_start();
+                inner = self.shared.space.wait(inner).unwrap();
+                inner.send_waited(since);
+            }
+
+            if inner.taken < ticket {
+                // the receiver went away without taking it. Receiver::drop leaves the queue of a
+                // rendezvous channel alone, so ours is still the (only) item in there
+                let t = inner
+                    .queue
+                    .pop_back()
+                    .expect("rendezvous item is still queued");
+                if let Some(stats) = &inner.stats {
+                    stats.unsent(inner.queue.len());
+                }
+                return Err(SendError(t));
+            }
+
+            return Ok(());
+        }
+
         drop(inner); // drop the lock
 
         // notify any waiting receiver when it sends
         self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// The receivers are all gone, a send would fail
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().receivers == 0
+    }
+
+    /// Start keeping stats for this channel, and list it in stats::all under `name`. Starts over
+    /// if it was already instrumented
+    pub fn instrument(&self, name: &str) {
+        self.shared.inner.lock().unwrap().instrument(name);
+    }
+
+    /// None if the channel isn't instrumented
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.shared.inner.lock().unwrap().stats()
+    }
+
+    /// How many of this sender's sends found the channel full and dropped an item, either the
+    /// oldest one in the channel or their own. Clones start counting from 0
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// How many of this sender's sends found the channel full and were merged into the newest item
+    pub fn merged(&self) -> u64 {
+        self.merged
+    }
+
+    // keeps score, and drops whatever was dropped. The lock has to be given up by now, T's Drop
+    // could do anything
+    fn count(&mut self, overflowed: Overflowed<T>) {
+        match overflowed {
+            Overflowed::Dropped(t) => {
+                self.dropped += 1;
+                drop(t);
+            }
+            Overflowed::Merged => self.merged += 1,
+        }
+    }
+
+    /// Send without blocking. For a bounded channel this fails if the channel is full (unless its
+    /// overflow policy makes room), for a rendezvous channel it fails unless the receiver is already
+    /// waiting in receive
+    pub fn try_send(&mut self, t: T) -> Result<(), TrySendError<T>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        if inner.receivers == 0 {
+            return Err(TrySendError::Disconnected(t));
+        }
+
+        let full = match inner.capacity {
+            None => false,
+            // the receiver takes it as soon as it wakes up, so we don't need to wait for that
+            Some(0) => inner.queue.len() >= inner.receivers_waiting,
+            Some(capacity) => inner.queue.len() >= capacity,
+        };
+
+        if inner.overflows() {
+            let overflowed = inner.overflow(t);
+            drop(inner);
+            self.count(overflowed);
+            return Ok(());
+        }
+
+        if full {
+            return Err(TrySendError::Full(t));
+        }
+
+        inner.queue.push_back(t);
+        inner.count_sent();
+        inner.wake_selectors();
+        drop(inner);
+
+        self.shared.available.notify_one();
+        Ok(())
     }
 }
 
@@ -93,31 +475,372 @@ impl<T> Receiver<T> {
         let mut inner = self.shared.inner.lock().unwrap();
 
         loop {
-            match inner.queue.pop_front() {
-                Some(t) => {
-                    if !inner.queue.is_empty() {
-                        // copy the contents of the queue into receiver's buffer so that the next
-                        // size(self.buffer) receives won't take the lock
-                        // we swap the empty buffer with the queue that has items to receive
-                        //
-                        // NOTE: The buffer will be empty here as we will always pop from the
-                        // buffer if it's not empty
-                        std::mem::swap(&mut self.buffer, &mut inner.queue);
-                    }
-
-                    return Some(t);
-                }
+            inner = match Self::take(&self.shared, &mut self.buffer, inner) {
+                Ok(t) => return Some(t),
+                Err(inner) => inner,
+            };
 
-                // if the sender count is 0, then just
-                // return as the channel is empty forever
-                None if inner.senders == 0 => return None,
-                None => {
-                    // this loop won't consume CPU cycles as it is put to sleep by the OS
-                    // wait gives up the lock
-                    inner = self.shared.available.wait(inner).unwrap();
-                }
+            // if the sender count is 0, then just
+            // return as the channel is empty forever
+            if inner.senders == 0 {
+                return None;
+            }
+
+            // this loop won't consume CPU cycles as it is put to sleep by the OS
+            // wait gives up the lock
+            inner.receivers_waiting += 1;
+            // a rendezvous send is ready now
+            inner.wake_selectors();
+            let since = inner.wait_start();
+            inner = self.shared.available.wait(inner).unwrap();
+            inner.recv_waited(since);
+            inner.receivers_waiting -= 1;
+        }
+    }
+
+    /// Receive without blocking
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.buffer.pop_front() {
+            return Ok(t);
+        }
+
+        let inner = self.shared.inner.lock().unwrap();
+        let disconnected = inner.senders == 0;
+
+        match Self::take(&self.shared, &mut self.buffer, inner) {
+            Ok(t) => Ok(t),
+            Err(_) if disconnected => Err(TryRecvError::Disconnected),
+            Err(_) => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_deadline(deadline),
+            // so far in the future it might as well be never
+            None => self.receive().ok_or(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        if let Some(t) = self.buffer.pop_front() {
+            return Ok(t);
+        }
+
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        loop {
+            inner = match Self::take(&self.shared, &mut self.buffer, inner) {
+                Ok(t) => return Ok(t),
+                Err(inner) => inner,
+            };
+
+            if inner.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            // wait_timeout can wake up spuriously, or because of a notify meant for someone else,
+            // so the time left is worked out again on every turn of the loop
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+
+            inner.receivers_waiting += 1;
+            // a rendezvous send is ready now
+            inner.wake_selectors();
+            let since = inner.wait_start();
+            inner = self
+                .shared
+                .available
+                .wait_timeout(inner, deadline - now)
+                .unwrap()
+                .0;
+            inner.recv_waited(since);
+            inner.receivers_waiting -= 1;
+        }
+    }
+
+    /// See Sender::instrument
+    pub fn instrument(&self, name: &str) {
+        self.shared.inner.lock().unwrap().instrument(name);
+    }
+
+    /// None if the channel isn't instrumented
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.shared.inner.lock().unwrap().stats()
+    }
+
+    /// Iterator that blocks for every item, ends once all the senders are gone
+    pub fn iter(&mut self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+
+    /// Iterator over whatever is in the channel right now, doesn't block
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+
+    // Take the next item out of the queue. If there is one the lock is given up, as a bounded
+    // channel has to notify its senders after taking an item and that's better done without
+    // holding it. If there isn't, the lock is handed back, so the caller can go to sleep on the
+    // condvar without a send sneaking in between. Takes the fields separately as the guard is
+    // still borrowing self.shared
+    fn take<'a>(
+        shared: &Shared<Inner<T>>,
+        buffer: &mut VecDeque<T>,
+        mut inner: MutexGuard<'a, Inner<T>>,
+    ) -> Result<T, MutexGuard<'a, Inner<T>>> {
+        let t = match inner.queue.pop_front() {
+            Some(t) => t,
+            None => return Err(inner),
+        };
+        inner.count_received(1);
+
+        if inner.capacity.is_some() {
+            // no batching for bounded channels. Everything we'd move into our buffer would make
+            // room in the queue, and the senders could go way past the capacity
+            inner.taken += 1;
+            inner.wake_selectors();
+            drop(inner);
+
+            // notify_all as a rendezvous sender might be waiting for its item to be taken while
+            // another one is waiting for the queue to be empty
+            shared.space.notify_all();
+        } else if inner.receivers == 1 {
+            // copy the contents of the queue into receiver's buffer so that the next
+            // size(self.buffer) receives won't take the lock
+            // we swap the empty buffer with the queue that has items to receive
+            //
+            // NOTE: The buffer will be empty here as we will always pop from the
+            // buffer if it's not empty
+            std::mem::swap(buffer, &mut inner.queue);
+            inner.count_received(buffer.len());
+        } else if !inner.queue.is_empty() {
+            // with other receivers around, taking everything would leave them sitting idle while
+            // we work through our buffer. Take our share of what was there, counting the item we
+            // just took, rounded down so a short queue isn't all ours, and leave the rest
+            let share = ((inner.queue.len() + 1) / inner.receivers).saturating_sub(1);
+            buffer.extend(inner.queue.drain(..share));
+            inner.count_received(share);
+
+            if !inner.queue.is_empty() {
+                drop(inner);
+                // there's more for someone else, and the send that put it there might have woken
+                // us instead of them
+                shared.available.notify_one();
+            }
+        }
+
+        Ok(t)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.receivers -= 1;
+
+        if inner.receivers > 0 {
+            // the other receivers are still around. Whatever we took into our buffer but didn't
+            // get to goes back to the front of the queue, in order, for one of them to take
+            let had_buffered = !self.buffer.is_empty();
+            if let Some(stats) = &inner.stats {
+                stats.returned(self.buffer.len(), inner.queue.len() + self.buffer.len());
+            }
+            while let Some(t) = self.buffer.pop_back() {
+                inner.queue.push_front(t);
+            }
+            if had_buffered {
+                inner.wake_selectors();
+            }
+            drop(inner);
+
+            if had_buffered {
+                self.shared.available.notify_all();
+            }
+            return;
+        }
+
+        inner.wake_selectors();
+
+        // nobody is going to receive what's still in the queue, so drop it now rather than
+        // whenever the last sender goes away. Except for a rendezvous channel, where the item in
+        // the queue still belongs to a sender that's waiting for it to be taken, and that sender
+        // takes it back
+        let queued = if inner.capacity == Some(0) {
+            VecDeque::new()
+        } else {
+            std::mem::take(&mut inner.queue)
+        };
+        if let Some(stats) = &inner.stats {
+            stats.depth(inner.queue.len());
+        }
+        drop(inner);
+
+        // wake up every sender blocked on a full channel so they can return their errors
+        self.shared.space.notify_all();
+
+        // dropped outside the lock, T's Drop could do anything (even send on this channel).
+        // self.buffer is dropped right after this, once drop returns
+        drop(queued);
+    }
+}
+
+// Select only gets to see the channels through these, so that it doesn't need to know about T
+pub(super) struct RecvHandle<T> {
+    shared: MyArc<Shared<Inner<T>>>,
+    // the receiver already has items in its own buffer, the queue doesn't tell us about those
+    buffered: bool,
+}
+
+pub(super) struct SendHandle<T> {
+    shared: MyArc<Shared<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    // mpmc::Receiver's clone. Starts with an empty buffer of its own
+    pub(super) fn clone_receiver(&self) -> Self {
+        self.shared.inner.lock().unwrap().receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub(super) fn select_handle(&self) -> RecvHandle<T> {
+        RecvHandle {
+            shared: self.shared.clone(),
+            buffered: !self.buffer.is_empty(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub(super) fn select_handle(&self) -> SendHandle<T> {
+        SendHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    // a send would have to go to the overflow policy. Never for Block, which waits for room (and
+    // which rendezvous channels always are)
+    fn overflows(&self) -> bool {
+        match (self.overflow, self.capacity) {
+            (OverflowPolicy::Block, _) | (_, None) => false,
+            (_, Some(capacity)) => self.queue.len() >= capacity,
+        }
+    }
+
+    // Put t in a full channel that doesn't block. Bounded channels don't batch into the receiver's
+    // buffer, so everything in the channel is in the queue, and since the capacity is at least 1
+    // there's something in there to drop or merge into. The queue doesn't get any longer, so
+    // there's nobody to wake up either
+    fn overflow(&mut self, t: T) -> Overflowed<T> {
+        let overflowed = match self.overflow {
+            OverflowPolicy::Block => unreachable!("a blocking channel never overflows"),
+            OverflowPolicy::DropOldest => {
+                let oldest = self.queue.pop_front().expect("a full channel is not empty");
+                self.queue.push_back(t);
+                Overflowed::Dropped(oldest)
+            }
+            OverflowPolicy::DropNewest => Overflowed::Dropped(t),
+            OverflowPolicy::Coalesce(merge) => {
+                let newest = self.queue.back_mut().expect("a full channel is not empty");
+                merge(newest, t);
+                Overflowed::Merged
             }
+        };
+
+        self.count_overflow(&overflowed);
+        overflowed
+    }
+
+    // a receive wouldn't block: there's something to take, or nothing will ever come
+    fn recv_ready(&self) -> bool {
+        !self.queue.is_empty() || self.senders == 0
+    }
+
+    // a try_send would go through, or fail with Disconnected
+    fn send_ready(&self) -> bool {
+        if self.receivers == 0 {
+            return true;
         }
+
+        match self.capacity {
+            None => true,
+            Some(0) => self.queue.len() < self.receivers_waiting,
+            Some(capacity) => {
+                self.queue.len() < capacity || !matches!(self.overflow, OverflowPolicy::Block)
+            }
+        }
+    }
+
+    fn watch(&mut self, signal: &MyArc<Signal>) {
+        self.selectors.push(signal.clone());
+    }
+
+    fn unwatch(&mut self, signal: &MyArc<Signal>) {
+        self.selectors.retain(|s| !MyArc::ptr_eq(s, signal));
+    }
+}
+
+impl<T> Selectable for RecvHandle<T> {
+    fn is_ready(&self) -> bool {
+        self.buffered || self.shared.inner.lock().unwrap().recv_ready()
+    }
+
+    fn watch(&self, signal: &MyArc<Signal>) -> bool {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.watch(signal);
+        self.buffered || inner.recv_ready()
+    }
+
+    fn unwatch(&self, signal: &MyArc<Signal>) {
+        self.shared.inner.lock().unwrap().unwatch(signal);
+    }
+}
+
+impl<T> Selectable for SendHandle<T> {
+    fn is_ready(&self) -> bool {
+        self.shared.inner.lock().unwrap().send_ready()
+    }
+
+    fn watch(&self, signal: &MyArc<Signal>) -> bool {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.watch(signal);
+        inner.send_ready()
+    }
+
+    fn unwatch(&self, signal: &MyArc<Signal>) {
+        self.shared.inner.lock().unwrap().unwatch(signal);
+    }
+}
+
+pub struct Iter<'a, T> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.receive()
+    }
+}
+
+pub struct TryIter<'a, T> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.try_recv().ok()
     }
 }
 
@@ -131,21 +854,48 @@ impl<T> Iterator for Receiver<T> {
 }
 
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None, OverflowPolicy::Block)
+}
+
+/// A bounded channel: send blocks while there are `capacity` items in the channel. With a capacity
+/// of 0 this is a rendezvous channel, send blocks until the receiver has taken the item
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    new_channel(Some(capacity), OverflowPolicy::Block)
+}
+
+/// A bounded channel that does what `policy` says once there are `capacity` items in it. Block is
+/// the same as sync_channel, with any of the others send never blocks
+pub fn bounded_channel<T>(capacity: usize, policy: OverflowPolicy<T>) -> (Sender<T>, Receiver<T>) {
+    assert!(
+        capacity > 0 || matches!(policy, OverflowPolicy::Block),
+        "a rendezvous channel has nothing to drop or merge into"
+    );
+    new_channel(Some(capacity), policy)
+}
+
+pub(super) fn new_channel<T>(
+    capacity: Option<usize>,
+    overflow: OverflowPolicy<T>,
+) -> (Sender<T>, Receiver<T>) {
     let inner = Inner {
         queue: VecDeque::default(),
         senders: 1,
+        capacity,
+        overflow,
+        taken: 0,
+        receivers_waiting: 0,
+        receivers: 1,
+        selectors: Vec::new(),
+        stats: None,
     };
 
-    let shared = Shared {
-        inner: Mutex::new(inner),
-        available: Condvar::new(),
-    };
-
-    let shared = Arc::new(shared);
+    let shared = MyArc::new(Shared::new(inner));
 
     (
         Sender {
             shared: shared.clone(),
+            dropped: 0,
+            merged: 0,
         },
         Receiver {
             shared: shared.clone(),
@@ -159,7 +909,7 @@ pub fn tests() {
 
     // ping pong
     let (mut tx, mut rx) = channel();
-    tx.send(42);
+    tx.send(42).unwrap();
     assert_eq!(rx.receive(), Some(42));
 
     // closed tx
@@ -171,5 +921,231 @@ pub fn tests() {
     // closed rx
     let (mut tx, mut rx) = channel();
     drop(rx);
-    tx.send(42); // we should tell that channel has been closed as there are no receivers
+    // we should tell that channel has been closed as there are no receivers
+    assert_eq!(tx.send(42), Err(SendError(42)));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn bounded_send_blocks_when_full() {
+        let sent: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+        let (mut tx, mut rx) = sync_channel(2);
+
+        let producer = spawn(move || {
+            for i in 0..5 {
+                tx.send(i).unwrap();
+                sent.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        // the producer can get 2 items in, and then has to wait for us
+        sleep(Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        assert_eq!(rx.receive(), Some(0));
+        sleep(Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        let rest: Vec<_> = rx.collect();
+        assert_eq!(rest, [1, 2, 3, 4]);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn try_send_on_a_full_channel() {
+        let (mut tx, mut rx) = sync_channel(1);
+
+        tx.try_send(1).unwrap();
+        assert!(matches!(tx.try_send(2), Err(TrySendError::Full(2))));
+
+        assert_eq!(rx.receive(), Some(1));
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.receive(), Some(3));
+
+        // unbounded channels are never full
+        let (mut tx, _rx) = channel();
+        for i in 0..100 {
+            tx.try_send(i).unwrap();
+        }
+    }
+
+    #[test]
+    fn rendezvous_send_waits_for_the_receiver() {
+        let received: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+        let (mut tx, mut rx) = sync_channel(0);
+
+        // nobody is waiting to take it
+        assert!(matches!(tx.try_send(1), Err(TrySendError::Full(1))));
+
+        let consumer = spawn(move || {
+            sleep(Duration::from_millis(50));
+            for _ in 0..3 {
+                rx.receive().unwrap();
+                received.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        for i in 0..3 {
+            tx.send(i).unwrap();
+            // send only returns once the item has been taken
+            assert!(received.load(Ordering::SeqCst) >= i);
+        }
+
+        consumer.join().unwrap();
+        assert_eq!(received.load(Ordering::SeqCst), 3);
+    }
+
+    struct DropLog(&'static str, MyArc<Mutex<Vec<&'static str>>>);
+
+    impl Drop for DropLog {
+        fn drop(&mut self) {
+            self.1.lock().unwrap().push(self.0);
+        }
+    }
+
+    #[test]
+    fn send_after_receiver_dropped_hands_the_item_back() {
+        let (mut tx, rx) = channel();
+        drop(rx);
+
+        assert_eq!(tx.send(1), Err(SendError(1)));
+        assert!(matches!(tx.try_send(2), Err(TrySendError::Disconnected(2))));
+    }
+
+    #[test]
+    fn queued_items_are_dropped_with_the_receiver() {
+        let log = MyArc::new(Mutex::new(vec![]));
+        let (mut tx, mut rx) = channel();
+
+        tx.send(DropLog("a", log.clone())).unwrap();
+        tx.send(DropLog("b", log.clone())).unwrap();
+        tx.send(DropLog("c", log.clone())).unwrap();
+
+        // takes a, and moves b and c into the receiver's buffer
+        drop(rx.receive());
+        tx.send(DropLog("d", log.clone())).unwrap();
+
+        // d is still in the shared queue, b and c in the buffer. All of them go away right here,
+        // not when the sender does
+        drop(rx);
+        assert_eq!(*log.lock().unwrap(), ["a", "d", "b", "c"]);
+
+        let Err(SendError(e)) = tx.send(DropLog("e", log.clone())) else {
+            panic!("receiver is gone");
+        };
+        drop(e);
+        drop(tx);
+        assert_eq!(*log.lock().unwrap(), ["a", "d", "b", "c", "e"]);
+    }
+
+    #[test]
+    fn blocked_senders_wake_up_when_the_receiver_leaves() {
+        let (mut tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+
+        let blocked = spawn(move || tx.send(2));
+        sleep(Duration::from_millis(50));
+        drop(rx);
+        assert_eq!(blocked.join().unwrap(), Err(SendError(2)));
+
+        // a rendezvous sender gets its item back if the receiver never takes it
+        let (mut tx, rx) = sync_channel(0);
+        let blocked = spawn(move || tx.send(String::from("hi")));
+        sleep(Duration::from_millis(50));
+        drop(rx);
+        assert_eq!(blocked.join().unwrap(), Err(SendError(String::from("hi"))));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let (mut tx, mut rx) = bounded_channel(2, OverflowPolicy::DropOldest);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(tx.dropped(), 3);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [3, 4]);
+
+        let (mut tx, mut rx) = bounded_channel(2, OverflowPolicy::DropNewest);
+        for i in 0..5 {
+            tx.try_send(i).unwrap();
+        }
+        let mut other = tx.clone();
+        other.send(5).unwrap();
+        assert_eq!((tx.dropped(), other.dropped()), (3, 1));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1]);
+
+        // merge into the newest item, the one that's already in there stays put
+        let (mut tx, mut rx) = bounded_channel(2, OverflowPolicy::Coalesce(|sum, i| *sum += i));
+        for i in 1..=5 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!((tx.merged(), tx.dropped()), (3, 0));
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1, 2 + 3 + 4 + 5]);
+
+        // only full channels overflow
+        tx.send(6).unwrap();
+        assert_eq!(tx.merged(), 3);
+        assert_eq!(rx.receive(), Some(6));
+    }
+
+    #[test]
+    fn try_recv_empty_and_disconnected() {
+        let (mut tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+
+        drop(tx);
+        // still there, even though the senders are gone
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn recv_timeout_and_deadline() {
+        let (mut tx, mut rx) = channel();
+
+        let start = Instant::now();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let sender = spawn(move || {
+            sleep(Duration::from_millis(20));
+            tx.send(7).unwrap();
+        });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(rx.recv_deadline(deadline), Ok(7));
+
+        sender.join().unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn iter_and_try_iter() {
+        let (mut tx, mut rx) = channel();
+
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(rx.try_iter().count(), 0);
+
+        tx.send(3).unwrap();
+        drop(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), [3]);
+    }
 }
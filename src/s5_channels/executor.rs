@@ -0,0 +1,156 @@
+// Just enough of an async runtime to drive the async channel without pulling in tokio.
+//
+// A future doesn't do anything until someone polls it, and when it can't make progress it hands
+// back Pending after stashing the Waker from the Context somewhere. Calling wake on that Waker
+// means "poll me again". So a runtime is: poll, and when everything is Pending, sleep until one of
+// the wakers is called.
+//
+// block_on runs one future on the current thread, parking it in between polls. The Executor does
+// the same for a bunch of tasks at once: each task gets a Waker that puts the task's index in the
+// ready queue (and unparks the executor's thread), and run only polls the tasks in there.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::{pin, Pin};
+// the std Arc and not MyArc, the Wake trait is only implemented for this one
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        // a wake before we get here leaves the unpark token behind, so park returns right away and
+        // we poll again. A spurious wakeup is just one more poll
+        thread::park();
+    }
+}
+
+struct ReadyQueue {
+    tasks: Mutex<VecDeque<usize>>,
+    thread: Thread,
+}
+
+struct TaskWaker {
+    task: usize,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // can end up in there more than once, polling a task one time too many is harmless
+        self.ready.tasks.lock().unwrap().push_back(self.task);
+        self.ready.thread.unpark();
+    }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Runs any number of tasks on the thread that calls `run`, switching between them whenever one
+/// of them is waiting
+pub struct Executor {
+    // None once the task is done
+    tasks: Vec<Option<Task>>,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: Vec::new(),
+            ready: Arc::new(ReadyQueue {
+                tasks: Mutex::new(VecDeque::new()),
+                thread: thread::current(),
+            }),
+        }
+    }
+
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        let task = self.tasks.len();
+        self.tasks.push(Some(Box::pin(future)));
+        // every task gets polled at least once
+        self.ready.tasks.lock().unwrap().push_back(task);
+    }
+
+    /// Run until every task is done. Has to be called on the thread that made the executor, that's
+    /// the one the wakers unpark
+    pub fn run(&mut self) {
+        assert_eq!(
+            thread::current().id(),
+            self.ready.thread.id(),
+            "Executor::run called on another thread"
+        );
+
+        while self.tasks.iter().any(Option::is_some) {
+            let next = self.ready.tasks.lock().unwrap().pop_front();
+
+            let task = match next {
+                Some(task) => task,
+                None => {
+                    // everything is waiting on something. A wake pushes to the queue first and
+                    // then unparks, so we can't miss it
+                    thread::park();
+                    continue;
+                }
+            };
+
+            let Some(future) = &mut self.tasks[task] else {
+                // finished already, this was a leftover wake
+                continue;
+            };
+
+            let waker = Waker::from(Arc::new(TaskWaker {
+                task,
+                ready: self.ready.clone(),
+            }));
+
+            if future
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_ready()
+            {
+                self.tasks[task] = None;
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+pub fn tests() {
+    println!("Testing executor");
+
+    assert_eq!(block_on(async { 1 + 1 }), 2);
+
+    let mut executor = Executor::new();
+    executor.spawn(async {});
+    executor.run();
+}
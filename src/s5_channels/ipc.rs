@@ -0,0 +1,674 @@
+// A channel between processes. Both ends of a UnixStream, with a Sender on one and a Receiver on
+// the other, and since a socket survives a fork (or can be connected to by path, see from_stream)
+// they can live in different processes on the same host.
+//
+// Nothing can be shared between the two ends, so every item is turned into bytes by a Codec and
+// written to the socket as a frame:
+//
+//   [ payload length: u32 LE | fd count: u32 LE | payload ... ]
+//
+// It's a stream socket, so a read can return half a frame, or a frame and a half. The receiver
+// keeps what it read in a buffer and only decodes a frame once all of it is there.
+//
+// File descriptors can go along with an item, see send_with_fds. They're passed with SCM_RIGHTS:
+// ancillary data on the sendmsg that writes the frame, which the kernel turns into new fds in the
+// receiving process. They arrive with the first byte of their frame, which is why the header says
+// how many there are: by the time a frame is complete its fds are in, and the ones after them
+// belong to the frames after it.
+//
+// Disconnection works the same as for channels.rs: once the receiver is gone a send fails with
+// SendError (the kernel says EPIPE), and once the sender is gone, and every frame it sent has been
+// received, receive returns None (the read says EOF). A process dying closes its end as well, so a
+// crashed peer looks like a hang-up. A socket that breaks some other way, or a frame that doesn't
+// decode, is taken as a hang-up too: there's no getting back in sync with the stream after that.
+
+use super::channels::{SendError, TryRecvError};
+use std::collections::VecDeque;
+use std::io;
+use std::marker::PhantomData;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::{mem, ptr};
+
+const HEADER: usize = 8;
+// at most this many fds per item. The kernel's own limit is 253
+const MAX_FDS: usize = 16;
+// how much the receiver asks for per read
+const CHUNK: usize = 4096;
+// the most zero sized items a Vec can be decoded with. They take no bytes, so the frame's length
+// doesn't limit how many a peer can claim there are
+const MAX_ZST_ITEMS: usize = 1 << 20;
+
+/// Returned by `Codec::decode` when the bytes aren't a valid encoding
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DecodeError(pub &'static str);
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not decode: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// How an item is turned into bytes and back again
+pub trait Codec: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Read one item off the front of `buf`, and move `buf` past it
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+// the next n bytes of buf
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if buf.len() < n {
+        return Err(DecodeError("unexpected end of frame"));
+    }
+
+    let (bytes, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(bytes)
+}
+
+// numbers are written little endian, whatever the host is
+macro_rules! number_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let bytes = take(buf, mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+number_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// usize as a u64, so both ends agree on the size even if one of them is a 32 bit process
+impl Codec for usize {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u64).encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        usize::try_from(u64::decode(buf)?).map_err(|_| DecodeError("usize out of range"))
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError("invalid bool")),
+        }
+    }
+}
+
+impl Codec for char {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as u32).encode(buf);
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        char::from_u32(u32::decode(buf)?).ok_or(DecodeError("invalid char"))
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _buf: &mut Vec<u8>) {}
+
+    fn decode(_buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+// length first, then the bytes
+impl Codec for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.len().encode(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = usize::decode(buf)?;
+        let bytes = take(buf, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError("invalid utf-8"))
+    }
+}
+
+// length first, then the items one after the other
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.len().encode(buf);
+        for t in self {
+            t.encode(buf);
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = usize::decode(buf)?;
+        // the length came from the other process, don't go allocating (or looping) whatever it
+        // says. Every item takes at least a byte, except for zero sized ones
+        let limit = match mem::size_of::<T>() {
+            0 => MAX_ZST_ITEMS,
+            _ => buf.len(),
+        };
+        if len > limit {
+            return Err(DecodeError("vec longer than the frame"));
+        }
+
+        let mut v = Vec::with_capacity(len);
+        for _ in 0..len {
+            v.push(T::decode(buf)?);
+        }
+        Ok(v)
+    }
+}
+
+macro_rules! tuple_codec {
+    ($($name:ident)+) => {
+        impl<$($name: Codec),+> Codec for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode(buf);)+
+            }
+
+            fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+                Ok(($($name::decode(buf)?,)+))
+            }
+        }
+    };
+}
+
+tuple_codec!(A);
+tuple_codec!(A B);
+tuple_codec!(A B C);
+tuple_codec!(A B C D);
+
+/// Sends over a socket. Not Clone: a frame takes more than one write, and frames from two senders
+/// could end up interleaved
+pub struct Sender<T> {
+    stream: UnixStream,
+    // reused for every frame
+    frame: Vec<u8>,
+    _item: PhantomData<fn(T)>,
+}
+
+pub struct Receiver<T> {
+    stream: UnixStream,
+    // what we've read but not decoded yet, starting at a frame boundary
+    buf: Vec<u8>,
+    // the fds we've been passed that haven't been handed out with their frame yet
+    fds: VecDeque<OwnedFd>,
+    // the sender hung up, or the stream broke. What's left in buf can still be received
+    closed: bool,
+    _item: PhantomData<fn() -> T>,
+}
+
+// Run a libc call that returns -1 and sets errno on failure, again if a signal got in the way
+fn retry(mut f: impl FnMut() -> isize) -> io::Result<usize> {
+    loop {
+        let n = f();
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+// room for a cmsghdr with MAX_FDS fds after it. u64s to get the alignment cmsghdr needs
+fn cmsg_buffer() -> Vec<u64> {
+    // SAFETY: CMSG_SPACE is just arithmetic
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0; space.div_ceil(mem::size_of::<u64>())]
+}
+
+impl<T: Codec> Sender<T> {
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Sender {
+            stream,
+            frame: Vec::new(),
+            _item: PhantomData,
+        }
+    }
+
+    /// Fails once the receiver is gone, handing the item back
+    pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
+        self.send_with_fds(t, &[])
+    }
+
+    /// Send `fds` along with the item. The receiver gets its own copies of them, ours stay open
+    pub fn send_with_fds(&mut self, t: T, fds: &[BorrowedFd<'_>]) -> Result<(), SendError<T>> {
+        assert!(fds.len() <= MAX_FDS, "at most {} fds per item", MAX_FDS);
+
+        self.frame.clear();
+        self.frame.extend_from_slice(&[0; HEADER]);
+        t.encode(&mut self.frame);
+
+        let len = u32::try_from(self.frame.len() - HEADER).expect("item too big for one frame");
+        self.frame[..4].copy_from_slice(&len.to_le_bytes());
+        self.frame[4..HEADER].copy_from_slice(&(fds.len() as u32).to_le_bytes());
+
+        match self.write_frame(fds) {
+            Ok(()) => Ok(()),
+            // EPIPE if the receiver is gone. Anything else leaves us somewhere in the middle of a
+            // frame, so the channel is no use anymore either way
+            Err(_) => Err(SendError(t)),
+        }
+    }
+
+    fn write_frame(&self, fds: &[BorrowedFd<'_>]) -> io::Result<()> {
+        let socket = self.stream.as_raw_fd();
+
+        let mut iov = libc::iovec {
+            iov_base: self.frame.as_ptr() as *mut libc::c_void,
+            iov_len: self.frame.len(),
+        };
+        let mut cmsg = cmsg_buffer();
+
+        // SAFETY: an all zeroes msghdr is an empty one
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if !fds.is_empty() {
+            let bytes = mem::size_of_val(fds);
+            msg.msg_control = cmsg.as_mut_ptr().cast();
+            // SAFETY: CMSG_SPACE is just arithmetic
+            msg.msg_controllen = unsafe { libc::CMSG_SPACE(bytes as u32) } as _;
+
+            // SAFETY: msg_control points to cmsg, which has room for a header and MAX_FDS fds, so
+            // there is a first header and its data fits. BorrowedFd is repr(transparent) over a
+            // RawFd
+            unsafe {
+                let header = libc::CMSG_FIRSTHDR(&msg);
+                (*header).cmsg_level = libc::SOL_SOCKET;
+                (*header).cmsg_type = libc::SCM_RIGHTS;
+                (*header).cmsg_len = libc::CMSG_LEN(bytes as u32) as _;
+                ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(header), bytes);
+            }
+        }
+
+        // MSG_NOSIGNAL: a receiver that's gone is an EPIPE, not a SIGPIPE that kills us. The fds go
+        // with the first byte, so once that's out the rest is plain sends
+        // SAFETY: msg and everything it points to lives until the call returns
+        let mut sent = retry(|| unsafe { libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL) })?;
+
+        while sent < self.frame.len() {
+            let rest = &self.frame[sent..];
+            // SAFETY: rest is valid for rest.len() bytes
+            sent += retry(|| unsafe {
+                libc::send(socket, rest.as_ptr().cast(), rest.len(), libc::MSG_NOSIGNAL)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Codec> Receiver<T> {
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Receiver {
+            stream,
+            buf: Vec::new(),
+            fds: VecDeque::new(),
+            closed: false,
+            _item: PhantomData,
+        }
+    }
+
+    /// Blocks for the next item. None once the sender is gone and everything it sent has been
+    /// received. Any fds sent with the item are closed, see receive_with_fds
+    pub fn receive(&mut self) -> Option<T> {
+        self.receive_with_fds().map(|(t, _)| t)
+    }
+
+    /// The next item, and the fds that were sent along with it
+    pub fn receive_with_fds(&mut self) -> Option<(T, Vec<OwnedFd>)> {
+        self.next_frame(true).ok()
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.next_frame(false).map(|(t, _)| t)
+    }
+
+    fn next_frame(&mut self, block: bool) -> Result<(T, Vec<OwnedFd>), TryRecvError> {
+        loop {
+            // whole frames that are already here go first, even once the sender is gone
+            match self.decode_frame() {
+                Some(Ok(frame)) => return Ok(frame),
+                Some(Err(_)) => {
+                    // we don't know where the next frame starts anymore
+                    self.closed = true;
+                    self.buf.clear();
+                    self.fds.clear();
+                    return Err(TryRecvError::Disconnected);
+                }
+                None => {}
+            }
+
+            if self.closed {
+                // if there's half a frame left in buf, the sender died halfway through it
+                return Err(TryRecvError::Disconnected);
+            }
+
+            match self.read(block) {
+                Ok(0) => self.closed = true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(TryRecvError::Empty),
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    // The frame at the front of buf, if all of it is there
+    fn decode_frame(&mut self) -> Option<Result<(T, Vec<OwnedFd>), DecodeError>> {
+        if self.buf.len() < HEADER {
+            return None;
+        }
+
+        let len = u32::from_le_bytes(self.buf[..4].try_into().unwrap()) as usize;
+        let fd_count = u32::from_le_bytes(self.buf[4..HEADER].try_into().unwrap()) as usize;
+
+        if self.buf.len() < HEADER + len {
+            return None;
+        }
+
+        // they came in with the first byte of the header, so they're here if they're coming at all
+        if self.fds.len() < fd_count {
+            return Some(Err(DecodeError("missing file descriptors")));
+        }
+        let fds = self.fds.drain(..fd_count).collect();
+
+        let mut payload = &self.buf[HEADER..HEADER + len];
+        let result = match T::decode(&mut payload) {
+            Ok(_) if !payload.is_empty() => Err(DecodeError("trailing bytes after the item")),
+            result => result,
+        };
+        self.buf.drain(..HEADER + len);
+
+        Some(result.map(|t| (t, fds)))
+    }
+
+    // Read whatever is there into buf, and the fds that came with it into fds. 0 means EOF
+    fn read(&mut self, block: bool) -> io::Result<usize> {
+        let socket = self.stream.as_raw_fd();
+        self.buf.reserve(CHUNK);
+        let filled = self.buf.len();
+
+        let mut iov = libc::iovec {
+            // SAFETY: still inside the allocation, we just reserved room after len
+            iov_base: unsafe { self.buf.as_mut_ptr().add(filled) }.cast(),
+            iov_len: self.buf.capacity() - filled,
+        };
+        let mut cmsg = cmsg_buffer();
+
+        // SAFETY: an all zeroes msghdr is an empty one
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&cmsg[..]) as _;
+
+        // CLOEXEC so the fds we get don't leak into whatever this process execs later
+        let mut flags = libc::MSG_CMSG_CLOEXEC;
+        if !block {
+            flags |= libc::MSG_DONTWAIT;
+        }
+
+        // SAFETY: msg and everything it points to lives until the call returns
+        let n = retry(|| unsafe { libc::recvmsg(socket, &mut msg, flags) })?;
+
+        // SAFETY: recvmsg initialized the n bytes after filled
+        unsafe { self.buf.set_len(filled + n) };
+
+        // SAFETY: recvmsg set msg_controllen to what it wrote into cmsg, and the CMSG macros stay
+        // inside that. The fds in an SCM_RIGHTS message are new ones the kernel opened for us, so
+        // they're ours to own
+        unsafe {
+            let mut header = libc::CMSG_FIRSTHDR(&msg);
+            while !header.is_null() {
+                if (*header).cmsg_level == libc::SOL_SOCKET
+                    && (*header).cmsg_type == libc::SCM_RIGHTS
+                {
+                    let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                    let bytes = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for i in 0..bytes / mem::size_of::<RawFd>() {
+                        let fd = ptr::read_unaligned(data.add(i));
+                        self.fds.push_back(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                header = libc::CMSG_NXTHDR(&msg, header);
+            }
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            // more fds than we had room for, the kernel closed the rest. A frame is going to be
+            // missing some
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file descriptors truncated",
+            ));
+        }
+
+        Ok(n)
+    }
+}
+
+impl<T: Codec> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receive()
+    }
+}
+
+/// A connected pair. To use it across processes, fork and keep one end on each side (and drop the
+/// other), or hand one end's socket to another process with send_with_fds
+pub fn channel<T: Codec>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let (tx, rx) = UnixStream::pair()?;
+    Ok((Sender::from_stream(tx), Receiver::from_stream(rx)))
+}
+
+pub fn tests() {
+    println!("Testing ipc");
+
+    let (mut tx, mut rx) = channel::<(String, Vec<u32>)>().unwrap();
+    tx.send((String::from("sum"), vec![1, 2, 3])).unwrap();
+    assert_eq!(rx.receive(), Some((String::from("sum"), vec![1, 2, 3])));
+
+    drop(tx);
+    assert_eq!(rx.receive(), None);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::fd::AsFd;
+    use std::process::{Child, Command, Stdio};
+
+    fn roundtrip<T: Codec + PartialEq + std::fmt::Debug>(t: T) {
+        let mut buf = Vec::new();
+        t.encode(&mut buf);
+        let mut bytes = &buf[..];
+        assert_eq!(T::decode(&mut bytes), Ok(t));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn codec_roundtrips() {
+        roundtrip(0xdead_beef_u32);
+        roundtrip(-5i64);
+        roundtrip(1.5f64);
+        roundtrip(usize::MAX);
+        roundtrip(true);
+        roundtrip('🦀');
+        roundtrip(String::from("héllo"));
+        roundtrip(vec![vec![1u8, 2], vec![]]);
+        roundtrip((1u8, String::from("a"), (), vec![false]));
+
+        let mut bad = &[2u8][..];
+        assert_eq!(bool::decode(&mut bad), Err(DecodeError("invalid bool")));
+        let mut short = &[1u8, 0, 0][..];
+        assert!(u32::decode(&mut short).is_err());
+
+        // a peer claiming more items than there can be in the frame, zero sized or not
+        roundtrip(vec![(); 3]);
+        let mut huge = Vec::new();
+        u64::MAX.encode(&mut huge);
+        assert!(Vec::<u32>::decode(&mut &huge[..]).is_err());
+        assert!(Vec::<()>::decode(&mut &huge[..]).is_err());
+    }
+
+    #[test]
+    fn partial_frames_and_hang_ups() {
+        let (mut tx, mut rx) = channel::<String>().unwrap();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        // more than one read's worth
+        let big = "x".repeat(3 * CHUNK);
+        tx.send(big.clone()).unwrap();
+        tx.send(String::from("after")).unwrap();
+        assert_eq!(rx.receive(), Some(big));
+
+        // what was sent before the hang-up still gets through
+        drop(tx);
+        assert_eq!(rx.try_recv().as_deref(), Ok("after"));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.receive(), None);
+
+        let (mut tx, rx) = channel::<u32>().unwrap();
+        drop(rx);
+        assert_eq!(tx.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn across_a_fork() {
+        let (mut tx, mut rx) = channel::<(u32, String)>().unwrap();
+
+        // SAFETY: the child only sends on the socket it inherited and leaves with _exit right
+        // away, it never returns into the test harness. It gets copies of whatever fds the other
+        // tests have open right now too, which is why it doesn't stick around: the longer it
+        // holds them, the longer those tests can't see their peers hang up
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0, "fork failed");
+
+        if pid == 0 {
+            let ok = tx.send((42, String::from("from the child"))).is_ok();
+            // SAFETY: just exits, without running anything of the parent's
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+
+        // the child's end. With this still open here we'd never see the child hang up
+        drop(tx);
+        assert_eq!(rx.receive(), Some((42, String::from("from the child"))));
+        assert_eq!(rx.receive(), None);
+
+        let mut status = 0;
+        // SAFETY: pid is our child
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    // The tests that need a second process for longer than across_a_fork does run it as this same
+    // test binary again, running only `test`, with CHILD set so the test knows it's the child. Not
+    // a fork: other tests are running on other threads, and a forked child would hold on to copies
+    // of whatever fds they have open until it exits. Exec closes those (they're all CLOEXEC),
+    // stdin is the only one it keeps
+    pub const CHILD: &str = "CHANNELS_TEST_CHILD";
+
+    pub fn spawn_child(test: &str, stdin: OwnedFd) -> Child {
+        Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads=1"])
+            .env(CHILD, "1")
+            .stdin(Stdio::from(stdin))
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    pub fn wait_child(child: Child) {
+        let output = child.wait_with_output().unwrap();
+        // the child's own test output, with its panic message if it failed
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    pub fn is_child() -> bool {
+        std::env::var_os(CHILD).is_some()
+    }
+
+    #[test]
+    fn across_processes_with_fds() {
+        if is_child() {
+            // the socket the parent gave us as stdin, with the requests on it
+            let stream = io::stdin().as_fd().try_clone_to_owned().unwrap();
+            let mut requests = Receiver::<(String, Vec<u64>)>::from_stream(stream.into());
+
+            // the pipe to write to, and the socket to reply on, came along with the request
+            let ((name, numbers), fds) = requests.receive_with_fds().unwrap();
+            let [out, replies] = <[OwnedFd; 2]>::try_from(fds).unwrap();
+            std::fs::File::from(out)
+                .write_all(b"hello from the child")
+                .unwrap();
+
+            let mut replies = Sender::from_stream(replies.into());
+            replies.send((name, numbers.iter().sum::<u64>())).unwrap();
+            // and then the parent hangs up
+            assert_eq!(requests.receive(), None);
+            return;
+        }
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let mut requests = Sender::<(String, Vec<u64>)>::from_stream(ours);
+        let (child_replies, mut replies) = channel::<(String, u64)>().unwrap();
+        let (mut pipe_rx, pipe_tx) = std::io::pipe().unwrap();
+
+        let child = spawn_child(
+            "s5_channels::ipc::tests::across_processes_with_fds",
+            theirs.into(),
+        );
+
+        requests
+            .send_with_fds(
+                (String::from("sum"), vec![1, 2, 3, 4]),
+                &[pipe_tx.as_fd(), child_replies.stream.as_fd()],
+            )
+            .unwrap();
+        // the child has its own copies now. With these still open here we'd never see it hang up
+        drop(pipe_tx);
+        drop(child_replies);
+
+        assert_eq!(replies.receive(), Some((String::from("sum"), 10)));
+        drop(requests);
+
+        let mut from_child = String::new();
+        pipe_rx.read_to_string(&mut from_child).unwrap();
+        assert_eq!(from_child, "hello from the child");
+
+        wait_child(child);
+
+        // the child is gone, and with it the other end
+        assert_eq!(replies.receive(), None);
+    }
+}
@@ -0,0 +1,473 @@
+// A channel where send never takes a lock.
+//
+// The queue is Dmitry Vyukov's MPSC queue: a singly linked list where the producers push at the
+// head with one atomic swap, and the single consumer pops at the tail without touching the
+// producers' end at all. There's always one node in the list that's already been consumed (the
+// stub), so the list is never really empty and neither end ever has to deal with a null pointer.
+//
+//   tail (consumer)                              head (producers)
+//      |                                            |
+//      v                                            v
+//    [stub] -next-> [a] -next-> [b] -next-> ... -> [z] -next-> null
+//
+// A push is: swap head to the new node, then link the old head's next to it. In between those two
+// steps the list is broken in two: the consumer sees a null next even though head has moved on.
+// That's the one place where the consumer has to wait for a producer (Inconsistent below), and it's
+// only ever for the two instructions between the swap and the store.
+//
+// The receiver only goes to sleep once the queue is empty, see Receiver::receive for how it avoids
+// missing a wakeup without a Mutex + Condvar.
+//
+// Sender and Receiver have the same methods as the ones of channel() in channels.rs, with a few
+// things left out on purpose:
+// - the Receiver can't be used with Select or select!. Those watch a channel by registering a
+//   Signal in its Inner, under its Mutex, and there's no Mutex here to register it under. The
+//   receiver parks its own thread instead, which a Select waiting on other channels too can't do
+// - no instrument/stats. Counting depth and waits would put shared counters back on the send path
+// - no overflow policies or dropped/merged counts, it's unbounded. try_send only ever fails because
+//   the receiver is gone
+
+use super::channels::{RecvTimeoutError, SendError, TryRecvError, TrySendError};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    // None for the stub
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> *mut Self {
+        Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value,
+        }))
+    }
+}
+
+enum Pop<T> {
+    Data(T),
+    Empty,
+    // a producer is in the middle of a push, it will be done in a moment
+    Inconsistent,
+}
+
+struct Queue<T> {
+    head: AtomicPtr<Node<T>>,
+    // only the consumer touches this
+    tail: UnsafeCell<*mut Node<T>>,
+}
+
+impl<T> Queue<T> {
+    fn new() -> Self {
+        let stub = Node::new(None);
+        Queue {
+            head: AtomicPtr::new(stub),
+            tail: UnsafeCell::new(stub),
+        }
+    }
+
+    fn push(&self, t: T) {
+        let node = Node::new(Some(t));
+
+        // AcqRel: Release so that the consumer, once it gets to our node, sees the value we wrote
+        // into it. Acquire so that we see the previous node fully initialized before we write to it
+        let prev = self.head.swap(node, Ordering::AcqRel);
+
+        // SAFETY: prev can't have been freed yet. The consumer only frees a node once it's moved
+        // past it, and it can't move past prev until we've set its next
+        unsafe { (*prev).next.store(node, Ordering::Release) };
+    }
+
+    // SAFETY: only one thread may pop at a time
+    unsafe fn pop(&self) -> Pop<T> {
+        let tail = *self.tail.get();
+        // Acquire pairs with the Release store in push, so the value in next is there for us
+        let next = (*tail).next.load(Ordering::Acquire);
+
+        if !next.is_null() {
+            // next becomes the new stub. Its value moves out, the old stub gets freed
+            *self.tail.get() = next;
+            let t = (*next).value.take().expect("only the stub has no value");
+            drop(Box::from_raw(tail));
+            return Pop::Data(t);
+        }
+
+        if self.head.load(Ordering::Acquire) == tail {
+            Pop::Empty
+        } else {
+            Pop::Inconsistent
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // nobody else is around anymore, just walk the list from the tail and free everything,
+        // dropping the values that were never received
+        let mut node = *self.tail.get_mut();
+        while !node.is_null() {
+            // SAFETY: every node was made by Box::into_raw and is only freed here or in pop
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+struct Shared<T> {
+    queue: Queue<T>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    // the receiver is about to park, or already parked. A sender that sees this wakes it up
+    sleeping: AtomicBool,
+    // only touched on the slow path, when the receiver goes to sleep and when it's woken up
+    receiver: Mutex<Option<Thread>>,
+}
+
+// The producers only touch head and the atomics, the tail (the UnsafeCell) is only touched by the
+// one Receiver, which needs &mut self to do so
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn wake_receiver(&self) {
+        // the load first, so that the common case (receiver is busy) is just a read of a cache
+        // line that isn't changing. SeqCst, see Receiver::receive
+        if self.sleeping.load(Ordering::SeqCst) && self.sleeping.swap(false, Ordering::SeqCst) {
+            if let Some(thread) = &*self.receiver.lock().unwrap() {
+                thread.unpark();
+            }
+        }
+    }
+}
+
+pub struct Sender<T> {
+    shared: MyArc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: MyArc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
+        if !self.shared.receiver_alive.load(Ordering::Acquire) {
+            return Err(SendError(t));
+        }
+
+        // if the receiver goes away right after the check the item just sits in the queue until
+        // the queue itself is dropped, same as with a receiver that never gets to it
+        self.shared.queue.push(t);
+
+        // the push and the check of sleeping can't be reordered, otherwise we could check before
+        // the receiver sets it, and the receiver could check the queue before our push lands
+        fence(Ordering::SeqCst);
+        self.shared.wake_receiver();
+
+        Ok(())
+    }
+
+    /// Never blocks anyway, there's no bound. Only fails once the receiver is gone
+    pub fn try_send(&mut self, t: T) -> Result<(), TrySendError<T>> {
+        self.send(t)
+            .map_err(|SendError(t)| TrySendError::Disconnected(t))
+    }
+
+    /// The receiver is gone, a send would fail
+    pub fn is_closed(&self) -> bool {
+        !self.shared.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        // Relaxed, same as MyArc::clone: we already are a sender, so the count can't hit 0
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Release so our pushes happen before the receiver sees the count go to 0
+        if self.shared.senders.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::SeqCst);
+            self.shared.wake_receiver();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    // spins through Inconsistent, a producer is two instructions away from fixing it
+    fn pop(&mut self) -> Option<T> {
+        loop {
+            // SAFETY: we're the only receiver, and we have a &mut to it
+            match unsafe { self.shared.queue.pop() } {
+                Pop::Data(t) => return Some(t),
+                Pop::Empty => return None,
+                Pop::Inconsistent => thread::yield_now(),
+            }
+        }
+    }
+
+    fn disconnected(&self) -> bool {
+        // Acquire pairs with the Release in Sender::drop, so every push of every sender is visible
+        // to the pop after this
+        self.shared.senders.load(Ordering::Acquire) == 0
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.pop() {
+            return Ok(t);
+        }
+
+        if self.disconnected() {
+            // the last sender might have pushed something right before it went away
+            return self.pop().ok_or(TryRecvError::Disconnected);
+        }
+
+        Err(TryRecvError::Empty)
+    }
+
+    pub fn receive(&mut self) -> Option<T> {
+        self.recv_until(None).ok()
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_deadline(deadline),
+            None => self.receive().ok_or(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(deadline))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            // slow path: the queue is empty, go to sleep. First say where to find us, then that
+            // we're sleeping, then look at the queue one more time. A sender does it the other way
+            // around: push, then look at sleeping. With SeqCst on both sides at least one of us sees
+            // the other's write, so either we find the item, or the sender finds us sleeping and
+            // wakes us up
+            *self.shared.receiver.lock().unwrap() = Some(thread::current());
+            self.shared.sleeping.store(true, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+
+            match self.try_recv() {
+                Err(TryRecvError::Empty) => {}
+                other => {
+                    self.shared.sleeping.store(false, Ordering::Relaxed);
+                    return other.map_err(|_| RecvTimeoutError::Disconnected);
+                }
+            }
+
+            // park can return spuriously, and an unpark from before can make it return right away.
+            // Either way we go around and check the queue again
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        self.shared.sleeping.store(false, Ordering::Relaxed);
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+
+            self.shared.sleeping.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Iterator that blocks for every item, ends once all the senders are gone
+    pub fn iter(&mut self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+
+    /// Iterator over whatever is in the channel right now, doesn't block
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter { rx: self }
+    }
+}
+
+pub struct Iter<'a, T> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.receive()
+    }
+}
+
+pub struct TryIter<'a, T> {
+    rx: &'a mut Receiver<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receive()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+
+        // drop what's queued now rather than whenever the last sender goes away
+        while self.pop().is_some() {}
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = MyArc::new(Shared {
+        queue: Queue::new(),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        sleeping: AtomicBool::new(false),
+        receiver: Mutex::new(None),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub fn tests() {
+    println!("Testing lockfree channel");
+
+    let (mut tx, mut rx) = channel();
+    tx.send(42).unwrap();
+    assert_eq!(rx.receive(), Some(42));
+
+    drop(tx);
+    assert_eq!(rx.receive(), None);
+
+    let (mut tx, rx) = channel();
+    drop(rx);
+    assert_eq!(tx.send(42), Err(SendError(42)));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn many_producers_keep_their_order() {
+        let (tx, mut rx) = channel();
+
+        let producers: Vec<_> = (0..8)
+            .map(|p| {
+                let mut tx = tx.clone();
+                spawn(move || {
+                    for i in 0..10_000 {
+                        tx.send((p, i)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        // no order between producers, but each one's items come out in the order it sent them
+        let mut next = [0; 8];
+        for (p, i) in rx.iter() {
+            assert_eq!(next[p], i);
+            next[p] += 1;
+        }
+        assert_eq!(next, [10_000; 8]);
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn receiver_parks_until_a_send() {
+        let (mut tx, mut rx) = channel();
+
+        // lots of rounds of: receiver goes to sleep on an empty queue, sender wakes it up. A missed
+        // wakeup hangs the test
+        let receiver = spawn(move || (0..1000).map(|_| rx.receive().unwrap()).sum::<u64>());
+
+        for i in 0..1000 {
+            if i % 100 == 0 {
+                sleep(Duration::from_millis(1));
+            }
+            tx.send(i).unwrap();
+        }
+
+        assert_eq!(receiver.join().unwrap(), (0..1000).sum());
+    }
+
+    #[test]
+    fn try_recv_and_timeout() {
+        let (mut tx, mut rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        tx.send(1).unwrap();
+        drop(tx);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn unreceived_items_are_dropped() {
+        let item = MyArc::new(());
+        let (mut tx, rx) = channel();
+        tx.send(item.clone()).unwrap();
+        tx.send(item.clone()).unwrap();
+        assert_eq!(MyArc::strong_count(&item), 3);
+
+        drop(rx);
+        assert_eq!(MyArc::strong_count(&item), 1);
+
+        // the receiver is gone, this one comes straight back
+        assert!(tx.is_closed());
+        assert!(tx.send(item.clone()).is_err());
+        assert!(matches!(
+            tx.try_send(item.clone()),
+            Err(TrySendError::Disconnected(_))
+        ));
+        assert_eq!(MyArc::strong_count(&item), 1);
+    }
+}
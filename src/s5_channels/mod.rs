@@ -1 +1,15 @@
+pub mod async_channel;
+pub mod benches;
+pub mod broadcast;
 pub mod channels;
+pub mod executor;
+pub mod ipc;
+pub mod lockfree;
+pub mod mpmc;
+pub mod oneshot;
+pub mod priority;
+pub mod select;
+pub mod spsc;
+pub mod stats;
+pub mod timer;
+pub mod watch;
@@ -0,0 +1,221 @@
+// Multi producer, multi consumer. Same channel as channels.rs, but the Receiver can be cloned, so a
+// pool of workers can all pull from one queue.
+//
+// Every item still goes to exactly one receiver: they all take items out of the one queue under the
+// lock. The batching is what changes. A single receiver swaps out the whole queue, with several of
+// them each one takes its share (queue length / receivers, rounded up) and leaves the rest for the
+// others. A receiver that's dropped with items left in its buffer puts them back in the queue.
+//
+// The channel is closed for the senders once the last receiver is gone, and for the receivers once
+// the last sender is gone.
+
+use super::channels::{self, OverflowPolicy, RecvTimeoutError, Sender, TryRecvError};
+use super::stats::ChannelStats;
+use std::time::{Duration, Instant};
+
+pub struct Receiver<T> {
+    inner: channels::Receiver<T>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            inner: self.inner.clone_receiver(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn receive(&mut self) -> Option<T> {
+        self.inner.receive()
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.inner.recv_timeout(timeout)
+    }
+
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        self.inner.recv_deadline(deadline)
+    }
+
+    pub fn instrument(&self, name: &str) {
+        self.inner.instrument(name)
+    }
+
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.inner.stats()
+    }
+
+    pub fn iter(&mut self) -> channels::Iter<'_, T> {
+        self.inner.iter()
+    }
+
+    pub fn try_iter(&mut self) -> channels::TryIter<'_, T> {
+        self.inner.try_iter()
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receive()
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = channels::new_channel(None, OverflowPolicy::Block);
+    (tx, Receiver { inner: rx })
+}
+
+/// Bounded, see channels::sync_channel
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = channels::new_channel(Some(capacity), OverflowPolicy::Block);
+    (tx, Receiver { inner: rx })
+}
+
+pub fn tests() {
+    println!("Testing mpmc");
+
+    let (mut tx, mut rx1) = channel();
+    let mut rx2 = rx1.clone();
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    drop(tx);
+
+    // either one can get any of them, but each only once
+    let mut got = vec![rx2.receive().unwrap(), rx1.receive().unwrap()];
+    got.sort();
+    assert_eq!(got, [1, 2]);
+    assert_eq!(rx1.receive(), None);
+    assert_eq!(rx2.receive(), None);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::s5_channels::channels::SendError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn every_item_goes_to_exactly_one_worker() {
+        let (tx, rx) = channel();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let rx = rx.clone();
+                spawn(move || rx.collect::<Vec<usize>>())
+            })
+            .collect();
+        drop(rx);
+
+        let producers: Vec<_> = (0..4)
+            .map(|p| {
+                let mut tx = tx.clone();
+                spawn(move || {
+                    for i in 0..1000 {
+                        tx.send(p * 1000 + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut all: Vec<_> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        all.sort();
+        assert_eq!(all, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn one_receiver_does_not_take_everything() {
+        let (mut tx, mut rx1) = channel();
+        let mut rx2 = rx1.clone();
+
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+
+        // rx1 takes 0, and then 1 to 4 into its buffer: half of the 10 that were there, the rest
+        // is still there for rx2
+        assert_eq!(rx1.receive(), Some(0));
+        assert_eq!(rx2.try_recv(), Ok(5));
+        assert_eq!(rx2.try_iter().collect::<Vec<_>>(), [6, 7, 8, 9]);
+        assert_eq!(rx1.try_iter().collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+        // as many items as receivers, one each
+        tx.send(10).unwrap();
+        tx.send(11).unwrap();
+        assert_eq!(rx1.receive(), Some(10));
+        assert_eq!(rx2.try_recv(), Ok(11));
+    }
+
+    #[test]
+    fn dropped_receiver_hands_back_its_buffer() {
+        let (mut tx, mut rx1) = channel();
+        let mut rx2 = rx1.clone();
+
+        for i in 0..4 {
+            tx.send(i).unwrap();
+        }
+
+        assert_eq!(rx1.receive(), Some(0));
+        // 1 is still in rx1's buffer
+        drop(rx1);
+
+        drop(tx);
+        assert_eq!(rx2.collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn disconnects_once_all_receivers_are_gone() {
+        let (mut tx, rx1) = sync_channel(1);
+        let rx2 = rx1.clone();
+
+        tx.send(1).unwrap();
+        drop(rx1);
+        // rx2 is still around, so this still waits for room
+        let sent: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+        let sender = spawn(move || {
+            let res = tx.send(2);
+            sent.fetch_add(1, Ordering::SeqCst);
+            res
+        });
+
+        sleep(Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+
+        drop(rx2);
+        assert_eq!(sender.join().unwrap(), Err(SendError(2)));
+    }
+
+    #[test]
+    fn last_sender_wakes_every_receiver() {
+        let (tx, rx) = channel::<i32>();
+
+        let waiting: Vec<_> = (0..4)
+            .map(|_| {
+                let mut rx = rx.clone();
+                spawn(move || rx.receive())
+            })
+            .collect();
+
+        sleep(Duration::from_millis(50));
+        drop(tx);
+
+        for handle in waiting {
+            assert_eq!(handle.join().unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+// A channel for exactly one value. send takes the sender by value, so sending twice doesn't
+// compile.
+//
+// There's no Mutex: the whole channel is one AtomicU8 plus two slots it guards, one for the value
+// and one for whoever's waiting for it (a parked thread, or a Waker for async).
+//
+//   EMPTY ----receiver registers a waiter----> RECEIVING
+//     ^  \                                       |   \
+//     |   \_____send_____> FULL <_____send_______/    \
+//     |                     |                          \
+//     |                     | receiver takes the value  \
+//     |                     v                            |
+//     |                  CLOSED <--- either side dropped-/
+//     \--receiver takes its waiter back (a new poll, a timeout)--/
+//
+// Who owns what:
+//  - the value slot belongs to the sender until it moves the state to FULL, and to the receiver
+//    from then on
+//  - the waiter slot belongs to the receiver while the state is EMPTY. Moving it to RECEIVING hands
+//    it over, and whoever moves it out of RECEIVING (the sender with its swap, or the receiver with
+//    a compare_exchange back to EMPTY) gets it back and is the one to take the waiter out
+
+use super::channels::{RecvTimeoutError, SendError, TryRecvError};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+const EMPTY: u8 = 0;
+const RECEIVING: u8 = 1;
+const FULL: u8 = 2;
+const CLOSED: u8 = 3;
+
+enum Waiter {
+    Thread(Thread),
+    Waker(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(thread) => thread.unpark(),
+            Waiter::Waker(waker) => waker.wake(),
+        }
+    }
+}
+
+struct Inner<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    waiter: UnsafeCell<Option<Waiter>>,
+}
+
+// the slots are only ever touched by the side that owns them, see the top of the file
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // both ends are gone. Receiver::drop already dropped a value that was never received, but
+        // there's no harm in being sure
+        if *self.state.get_mut() == FULL {
+            // SAFETY: FULL means the value was written and nobody took it
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+pub struct OneshotSender<T> {
+    inner: MyArc<Inner<T>>,
+}
+
+pub struct OneshotReceiver<T> {
+    inner: MyArc<Inner<T>>,
+}
+
+/// Returned by `recv` when the sender was dropped without sending anything
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecvError;
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the sender was dropped without sending")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+impl<T> OneshotSender<T> {
+    /// Fails if the receiver is gone, handing the value back
+    pub fn send(self, t: T) -> Result<(), SendError<T>> {
+        // our Drop would close the channel, and we're about to fill it instead
+        let this = ManuallyDrop::new(self);
+        // SAFETY: this is never used again, or dropped, so the MyArc is moved out exactly once
+        let inner = unsafe { std::ptr::read(&this.inner) };
+
+        // SAFETY: the value slot is ours until the state goes to FULL
+        unsafe { (*inner.value.get()).write(t) };
+
+        // Release: the receiver that sees FULL sees the value. Acquire: if it's RECEIVING we see
+        // the waiter the receiver put in before it got there
+        match inner.state.swap(FULL, Ordering::AcqRel) {
+            EMPTY => Ok(()),
+            RECEIVING => {
+                // SAFETY: we moved it out of RECEIVING, the waiter is ours now
+                let waiter = unsafe { (*inner.waiter.get()).take() };
+                if let Some(waiter) = waiter {
+                    waiter.wake();
+                }
+                Ok(())
+            }
+            CLOSED => {
+                // the receiver is gone, and it won't come back to look. Take the value back out and
+                // put things back the way they were
+                inner.state.store(CLOSED, Ordering::Relaxed);
+                // SAFETY: we just wrote it, and nobody else is going to read it
+                let t = unsafe { (*inner.value.get()).assume_init_read() };
+                Err(SendError(t))
+            }
+            _ => unreachable!("oneshot was filled twice"),
+        }
+    }
+
+    /// The receiver is gone, a send would fail
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.load(Ordering::Relaxed) == CLOSED
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        // dropped without sending
+        if self.inner.state.swap(CLOSED, Ordering::AcqRel) == RECEIVING {
+            // SAFETY: we moved it out of RECEIVING, the waiter is ours now
+            let waiter = unsafe { (*self.inner.waiter.get()).take() };
+            if let Some(waiter) = waiter {
+                waiter.wake();
+            }
+        }
+    }
+}
+
+impl<T> OneshotReceiver<T> {
+    // SAFETY: the state must be FULL. Leaves it CLOSED, so nobody looks at the value again
+    unsafe fn take_value(&mut self) -> T {
+        let t = (*self.inner.value.get()).assume_init_read();
+        self.inner.state.store(CLOSED, Ordering::Relaxed);
+        t
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        // Acquire pairs with the Release swap in send
+        match self.inner.state.load(Ordering::Acquire) {
+            // SAFETY: it's FULL
+            FULL => Ok(unsafe { self.take_value() }),
+            CLOSED => Err(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Block until the value is there, or the sender is gone
+    pub fn recv(mut self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError)
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_until(Some(deadline)),
+            None => self.recv_until(None),
+        }
+    }
+
+    // Put a waiter in the slot and move EMPTY to RECEIVING. Returns false if the state changed
+    // in the meantime, in which case the waiter has been thrown away again
+    fn register(&mut self, waiter: Waiter) -> bool {
+        // SAFETY: the state is EMPTY (or we're about to find out it isn't, see below), so the
+        // waiter slot is ours
+        unsafe { *self.inner.waiter.get() = Some(waiter) };
+
+        // Release: the sender that moves it out of RECEIVING sees the waiter
+        match self.inner.state.compare_exchange(
+            EMPTY,
+            RECEIVING,
+            Ordering::Release,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => true,
+            Err(_) => {
+                // filled or closed before we got to it. Nobody else looks at the waiter unless the
+                // state is RECEIVING, so it's still ours
+                unsafe { *self.inner.waiter.get() = None };
+                false
+            }
+        }
+    }
+
+    // Move RECEIVING back to EMPTY and throw our waiter away. Returns false if the sender got there
+    // first, in which case it has the waiter
+    fn unregister(&mut self) -> bool {
+        match self.inner.state.compare_exchange(
+            RECEIVING,
+            EMPTY,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // SAFETY: we moved it out of RECEIVING
+                unsafe { *self.inner.waiter.get() = None };
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.inner.state.load(Ordering::Acquire) {
+                // SAFETY: it's FULL
+                FULL => return Ok(unsafe { self.take_value() }),
+                CLOSED => return Err(RecvTimeoutError::Disconnected),
+                RECEIVING => {
+                    // we're registered already. park can wake up spuriously, so this just goes
+                    // around until the sender has moved the state on
+                    match deadline {
+                        None => thread::park(),
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now < deadline {
+                                thread::park_timeout(deadline - now);
+                            } else if self.unregister() {
+                                return Err(RecvTimeoutError::Timeout);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    self.register(Waiter::Thread(thread::current()));
+                }
+            }
+        }
+    }
+}
+
+// the value lives behind the MyArc, nothing about the receiver itself cares about being moved
+impl<T> Unpin for OneshotReceiver<T> {}
+
+impl<T> Future for OneshotReceiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match this.inner.state.load(Ordering::Acquire) {
+                // SAFETY: it's FULL
+                FULL => return Poll::Ready(Ok(unsafe { this.take_value() })),
+                CLOSED => return Poll::Ready(Err(RecvError)),
+                RECEIVING => {
+                    // polled again, possibly with a different waker. Take the old one back out
+                    // and go around to register the new one. If the sender beat us to it, the
+                    // state has moved on and we go around to look at that instead
+                    this.unregister();
+                }
+                _ => {
+                    if this.register(Waiter::Waker(cx.waker().clone())) {
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        match self.inner.state.swap(CLOSED, Ordering::AcqRel) {
+            // sent, but never received
+            // SAFETY: FULL, and now CLOSED so the sender (who's done anyway) won't look
+            FULL => unsafe { (*self.inner.value.get()).assume_init_drop() },
+            // a recv future that was dropped while it was waiting. We moved it out of RECEIVING,
+            // so the waiter is ours to throw away
+            RECEIVING => unsafe { *self.inner.waiter.get() = None },
+            _ => {}
+        }
+    }
+}
+
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let inner = MyArc::new(Inner {
+        state: AtomicU8::new(EMPTY),
+        value: UnsafeCell::new(MaybeUninit::uninit()),
+        waiter: UnsafeCell::new(None),
+    });
+
+    (
+        OneshotSender {
+            inner: inner.clone(),
+        },
+        OneshotReceiver { inner },
+    )
+}
+
+pub fn tests() {
+    println!("Testing oneshot");
+
+    let (tx, rx) = oneshot();
+    tx.send(42).unwrap();
+    assert_eq!(rx.recv(), Ok(42));
+
+    let (tx, rx) = oneshot::<i32>();
+    drop(tx);
+    assert_eq!(rx.recv(), Err(RecvError));
+
+    let (tx, rx) = oneshot();
+    drop(rx);
+    assert_eq!(tx.send(42), Err(SendError(42)));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::executor::{block_on, Executor};
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread::spawn;
+
+    struct DropCount(&'static AtomicUsize);
+
+    impl Drop for DropCount {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn blocking_send_and_receive_race() {
+        for i in 0..1000 {
+            let (tx, rx) = oneshot();
+            let sender = spawn(move || tx.send(i).unwrap());
+            assert_eq!(rx.recv(), Ok(i));
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn sender_dropped_without_sending_races_with_recv() {
+        for _ in 0..1000 {
+            let (tx, rx) = oneshot::<i32>();
+            let sender = spawn(move || drop(tx));
+            assert_eq!(rx.recv(), Err(RecvError));
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn receiver_dropped_without_receiving_races_with_send() {
+        let drops: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+
+        for _ in 0..1000 {
+            let (tx, rx) = oneshot();
+            let receiver = spawn(move || drop(rx));
+            // either it's in before the receiver goes, and the receiver drops it, or it comes
+            // back to us and we drop it. Never both, never neither
+            let _ = tx.send(DropCount(drops));
+            receiver.join().unwrap();
+        }
+
+        assert_eq!(drops.load(Ordering::Relaxed), 1000);
+    }
+
+    #[test]
+    fn timeout_then_send() {
+        let (tx, mut rx) = oneshot();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        tx.send(1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn awaited_from_another_thread() {
+        for i in 0..1000 {
+            let (tx, rx) = oneshot();
+            let sender = spawn(move || tx.send(i).unwrap());
+            assert_eq!(block_on(rx), Ok(i));
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn pending_future_dropped_while_sending() {
+        let drops: &'static _ = Box::leak(Box::new(AtomicUsize::new(0)));
+
+        for _ in 0..1000 {
+            let (tx, mut rx) = oneshot();
+
+            // registers a waker, then goes away without being polled again
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(Pin::new(&mut rx).poll(&mut cx).is_pending());
+
+            let receiver = spawn(move || drop(rx));
+            let _ = tx.send(DropCount(drops));
+            receiver.join().unwrap();
+        }
+
+        assert_eq!(drops.load(Ordering::Relaxed), 1000);
+    }
+
+    #[test]
+    fn tasks_hand_values_over() {
+        let (tx1, rx1) = oneshot();
+        let (tx2, rx2) = oneshot();
+
+        let mut executor = Executor::new();
+        executor.spawn(async move {
+            let n = rx1.await.unwrap();
+            tx2.send(n * 2).unwrap();
+        });
+        executor.spawn(async move {
+            tx1.send(21).unwrap();
+            assert_eq!(rx2.await, Ok(42));
+        });
+        executor.run();
+    }
+}
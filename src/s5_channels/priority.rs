@@ -0,0 +1,327 @@
+// A channel that hands out the most important item first, rather than the oldest. So a "stop" sent
+// after thousands of bulk items doesn't have to wait for all of them to be received.
+//
+// Inner keeps a BinaryHeap instead of a VecDeque, with every item under the priority it was sent
+// with. A heap doesn't keep the order things went in, so each item also gets a sequence number, and
+// among items of the same priority the lowest number goes first. That keeps them FIFO, same as in
+// channel().
+//
+// Otherwise it behaves like channel(): unbounded, send never blocks and fails once the receiver is
+// gone, receive blocks until there's something, and returns None once the senders are all gone and
+// everything has been received.
+//
+// The batching into the receiver's buffer is trickier here. In channels.rs the receiver can take
+// everything in one go because whatever is sent later goes after it anyway. Here a later send can
+// have a higher priority than what's sitting in the buffer, and it would have to wait for the whole
+// buffer to be received. So the receiver only batches once the senders are all gone: then nothing
+// can come along to jump the queue anymore, and the rest of the heap is drained in one go.
+
+use super::channels::{RecvTimeoutError, SendError, Shared, TryRecvError};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::MutexGuard;
+use std::time::{Duration, Instant};
+
+struct Entry<P, T> {
+    priority: P,
+    // order of sending, the tiebreak for equal priorities
+    seq: u64,
+    item: T,
+}
+
+// BinaryHeap is a max-heap: the highest priority comes out first, and of those the lowest seq,
+// i.e. the one sent first
+impl<P: Ord, T> Ord for Entry<P, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| Reverse(self.seq).cmp(&Reverse(other.seq)))
+    }
+}
+
+impl<P: Ord, T> PartialOrd for Entry<P, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// seqs are unique, so two entries are only equal if they're the same one
+impl<P: Ord, T> PartialEq for Entry<P, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<P: Ord, T> Eq for Entry<P, T> {}
+
+struct Inner<P, T> {
+    heap: BinaryHeap<Entry<P, T>>,
+    next_seq: u64,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+pub struct Sender<P, T> {
+    shared: MyArc<Shared<Inner<P, T>>>,
+}
+
+pub struct Receiver<P, T> {
+    shared: MyArc<Shared<Inner<P, T>>>,
+    // what was left in the heap once the senders were gone, lowest priority first so that the
+    // next one to receive is at the end
+    buffer: Vec<T>,
+}
+
+impl<P, T> Clone for Sender<P, T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<P, T> Drop for Sender<P, T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.senders -= 1;
+        let was_last = inner.senders == 0;
+        drop(inner);
+
+        if was_last {
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+impl<P: Ord, T> Sender<P, T> {
+    /// Higher priorities are received first, equal ones in the order they were sent. Fails if the
+    /// receiver has been dropped, handing the item back
+    pub fn send(&mut self, priority: P, t: T) -> Result<(), SendError<T>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        if !inner.receiver_alive {
+            return Err(SendError(t));
+        }
+
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(Entry {
+            priority,
+            seq,
+            item: t,
+        });
+        drop(inner);
+
+        self.shared.available.notify_one();
+        Ok(())
+    }
+}
+
+impl<P: Ord, T> Receiver<P, T> {
+    pub fn receive(&mut self) -> Option<T> {
+        if let Some(t) = self.buffer.pop() {
+            return Some(t);
+        }
+
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        loop {
+            if let Some(t) = Self::take(&mut self.buffer, &mut inner) {
+                return Some(t);
+            }
+
+            if inner.senders == 0 {
+                return None;
+            }
+
+            inner = self.shared.available.wait(inner).unwrap();
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(t) = self.buffer.pop() {
+            return Ok(t);
+        }
+
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        match Self::take(&mut self.buffer, &mut inner) {
+            Some(t) => Ok(t),
+            None if inner.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        if let Some(t) = self.buffer.pop() {
+            return Ok(t);
+        }
+
+        let deadline = Instant::now().checked_add(timeout);
+        let mut inner = self.shared.inner.lock().unwrap();
+
+        loop {
+            if let Some(t) = Self::take(&mut self.buffer, &mut inner) {
+                return Ok(t);
+            }
+
+            if inner.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            inner = match deadline {
+                // so far in the future it might as well be never
+                None => self.shared.available.wait(inner).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+
+                    let wait = self.shared.available.wait_timeout(inner, deadline - now);
+                    wait.unwrap().0
+                }
+            };
+        }
+    }
+
+    // The most important item in the heap. Once the senders are gone whatever is left goes into
+    // the buffer as well, in order, so the receives after this one don't need the lock
+    fn take(buffer: &mut Vec<T>, inner: &mut MutexGuard<'_, Inner<P, T>>) -> Option<T> {
+        let t = inner.heap.pop()?.item;
+
+        if inner.senders == 0 && !inner.heap.is_empty() {
+            // ascending, so the highest priority ends up last, where pop takes it from
+            let rest = std::mem::take(&mut inner.heap).into_sorted_vec();
+            buffer.extend(rest.into_iter().map(|entry| entry.item));
+        }
+
+        Some(t)
+    }
+}
+
+impl<P, T> Drop for Receiver<P, T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.receiver_alive = false;
+        let queued = std::mem::take(&mut inner.heap);
+        drop(inner);
+
+        // outside the lock, same as channels.rs
+        drop(queued);
+    }
+}
+
+impl<P: Ord, T> Iterator for Receiver<P, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receive()
+    }
+}
+
+/// An unbounded channel where the receiver gets the item with the highest priority first
+pub fn priority_channel<P: Ord, T>() -> (Sender<P, T>, Receiver<P, T>) {
+    let inner = Inner {
+        heap: BinaryHeap::new(),
+        next_seq: 0,
+        senders: 1,
+        receiver_alive: true,
+    };
+    let shared = MyArc::new(Shared::new(inner));
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver {
+            shared,
+            buffer: Vec::new(),
+        },
+    )
+}
+
+pub fn tests() {
+    println!("Testing priority channel");
+
+    let (mut tx, mut rx) = priority_channel();
+    tx.send(0, "bulk").unwrap();
+    tx.send(9, "stop").unwrap();
+    assert_eq!(rx.receive(), Some("stop"));
+    assert_eq!(rx.receive(), Some("bulk"));
+
+    drop(tx);
+    assert_eq!(rx.receive(), None);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn highest_priority_first_and_fifo_within_one() {
+        let (mut tx, mut rx) = priority_channel();
+
+        for i in 0..1000 {
+            tx.send(0, i).unwrap();
+        }
+        tx.send(1, -1).unwrap();
+        tx.send(5, -2).unwrap();
+        tx.send(1, -3).unwrap();
+
+        assert_eq!(rx.try_recv(), Ok(-2));
+        assert_eq!(rx.try_recv(), Ok(-1));
+        assert_eq!(rx.try_recv(), Ok(-3));
+        for i in 0..1000 {
+            assert_eq!(rx.try_recv(), Ok(i));
+        }
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn batches_only_once_the_senders_are_gone() {
+        let (mut tx, mut rx) = priority_channel();
+        tx.send(1, "a").unwrap();
+        tx.send(1, "b").unwrap();
+
+        // the senders are still around, so only one item is taken at a time
+        assert_eq!(rx.receive(), Some("a"));
+        assert!(rx.buffer.is_empty());
+
+        // and something more important still gets in ahead of b
+        tx.send(2, "urgent").unwrap();
+        tx.send(0, "c").unwrap();
+        assert_eq!(rx.receive(), Some("urgent"));
+
+        drop(tx);
+        assert_eq!(rx.receive(), Some("b"));
+        assert_eq!(rx.buffer, ["c"]);
+        assert_eq!(rx.collect::<Vec<_>>(), ["c"]);
+    }
+
+    #[test]
+    fn blocks_and_disconnects_like_channel() {
+        let (mut tx, mut rx) = priority_channel();
+
+        let sender = spawn(move || {
+            sleep(Duration::from_millis(50));
+            tx.send(3, String::from("hi")).unwrap();
+        });
+
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(rx.receive().as_deref(), Some("hi"));
+        sender.join().unwrap();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (mut tx, rx) = priority_channel();
+        drop(rx);
+        assert_eq!(tx.send(0, 1), Err(SendError(1)));
+    }
+}
@@ -0,0 +1,550 @@
+// Waiting on whichever of a bunch of channels is ready first.
+//
+// Each channel can only wake up the threads waiting on its own Condvar, so a Select brings its own
+// Signal (a Mutex<bool> + Condvar) and hangs it on every channel it's waiting on. Whenever something
+// happens on one of them that could make an operation ready (a send, a receive making room, one of
+// the ends going away) the channel fires every Signal it has. The woken Select then goes and checks
+// all of its channels again, just like a receiver re-checks the queue after waking up.
+//
+// Select only says *which* operation is ready, it's up to the caller to then do it with try_recv /
+// try_send. The select! macro does both, and goes back to waiting if it loses a race for the item
+// or the free slot.
+//
+// Doesn't work for both ends of a rendezvous channel at once: a receiver that's selecting isn't
+// sitting in receive, so a selecting sender never sees it as ready.
+
+use super::channels::{Receiver, Sender};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use rand::Rng;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub(super) struct Signal {
+    fired: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Signal {
+    pub(super) fn fire(&self) {
+        *self.fired.lock().unwrap() = true;
+        self.cond.notify_one();
+    }
+}
+
+// One end of a channel, with the T erased
+pub(super) trait Selectable {
+    fn is_ready(&self) -> bool;
+    /// Start firing `signal` on every change. Returns whether it's ready right now, checked under
+    /// the same lock, so nothing can slip in between the check and the registration
+    fn watch(&self, signal: &MyArc<Signal>) -> bool;
+    fn unwatch(&self, signal: &MyArc<Signal>);
+}
+
+/// Returned by `try_ready` when none of the operations are ready
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TryReadyError;
+
+impl std::fmt::Display for TryReadyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no operation is ready")
+    }
+}
+
+impl std::error::Error for TryReadyError {}
+
+/// Returned by `ready_timeout` / `ready_deadline` when none of the operations got ready in time
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ReadyTimeoutError;
+
+impl std::fmt::Display for ReadyTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("timed out waiting for an operation to be ready")
+    }
+}
+
+impl std::error::Error for ReadyTimeoutError {}
+
+pub struct Select<'a> {
+    ops: Vec<Box<dyn Selectable + 'a>>,
+    signal: MyArc<Signal>,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Select {
+            ops: Vec::new(),
+            signal: MyArc::new(Signal {
+                fired: Mutex::new(false),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Add a receive, returns its index. Ready when there's an item, or all the senders are gone
+    pub fn recv<T: 'a>(&mut self, rx: &Receiver<T>) -> usize {
+        self.ops.push(Box::new(rx.select_handle()));
+        self.ops.len() - 1
+    }
+
+    /// Add a send, returns its index. Ready when there's room, or the receiver is gone
+    pub fn send<T: 'a>(&mut self, tx: &Sender<T>) -> usize {
+        self.ops.push(Box::new(tx.select_handle()));
+        self.ops.len() - 1
+    }
+
+    pub fn try_ready(&mut self) -> Result<usize, TryReadyError> {
+        self.find_ready().ok_or(TryReadyError)
+    }
+
+    /// Block until one of the operations is ready
+    pub fn ready(&mut self) -> usize {
+        assert!(!self.ops.is_empty(), "select with no operations");
+        self.wait(None).unwrap()
+    }
+
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Result<usize, ReadyTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.ready_deadline(deadline),
+            None => Ok(self.ready()),
+        }
+    }
+
+    pub fn ready_deadline(&mut self, deadline: Instant) -> Result<usize, ReadyTimeoutError> {
+        self.wait(Some(deadline)).ok_or(ReadyTimeoutError)
+    }
+
+    // Start looking at a random operation, otherwise the first one would always win whenever
+    // several of them are ready, and a busy channel could starve all the others
+    fn start(&self) -> usize {
+        rand::thread_rng().gen_range(0..self.ops.len())
+    }
+
+    fn find_ready(&self) -> Option<usize> {
+        if self.ops.is_empty() {
+            return None;
+        }
+
+        let start = self.start();
+        let n = self.ops.len();
+        (0..n)
+            .map(|i| (start + i) % n)
+            .find(|&i| self.ops[i].is_ready())
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Option<usize> {
+        loop {
+            if let Some(i) = self.find_ready() {
+                return Some(i);
+            }
+
+            *self.signal.fired.lock().unwrap() = false;
+
+            // register with every channel before going to sleep. Something might have gotten
+            // ready since find_ready looked, watch tells us about that
+            let start = if self.ops.is_empty() { 0 } else { self.start() };
+            let n = self.ops.len();
+            let mut ready = None;
+            for i in (0..n).map(|i| (start + i) % n) {
+                if self.ops[i].watch(&self.signal) && ready.is_none() {
+                    ready = Some(i);
+                }
+            }
+
+            let timed_out = ready.is_none() && !self.sleep(deadline);
+
+            for op in &self.ops {
+                op.unwatch(&self.signal);
+            }
+
+            if ready.is_some() {
+                return ready;
+            }
+
+            if timed_out {
+                // one last look, something might have happened right as we timed out
+                return self.find_ready();
+            }
+        }
+    }
+
+    // false if the deadline passed before the signal fired. Spurious wakeups just loop around
+    fn sleep(&self, deadline: Option<Instant>) -> bool {
+        let mut fired = self.signal.fired.lock().unwrap();
+
+        while !*fired {
+            match deadline {
+                None => fired = self.signal.cond.wait(fired).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    fired = self
+                        .signal
+                        .cond
+                        .wait_timeout(fired, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Select::new()
+    }
+}
+
+/// Wait on several channel operations at once, and run the arm of the one that goes through.
+///
+/// ```ignore
+/// select! {
+///     recv(rx) -> msg => println!("got {:?}", msg),   // msg: Option<T>, None once disconnected
+///     send(tx, 42) -> res => res.unwrap(),            // res: Result<(), SendError<T>>
+///     default(Duration::from_secs(1)) => println!("timed out"),
+/// }
+/// ```
+///
+/// `default => ..` runs right away if nothing is ready. `default(timeout) => ..` runs if nothing
+/// got ready in time. Without a default arm it blocks. The channel expressions are evaluated every
+/// time the operations are (re)registered, so pass the channels themselves, not `&mut rx`. The value
+/// to send is only evaluated once its arm is picked.
+#[macro_export]
+macro_rules! select {
+    // first turn the arms into a list of (storage ident, kind, args..), and pull the default arm
+    // out, it doesn't go into the Select
+    (@parse [] $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@emit $ops $default)
+    };
+    (@parse [recv($rx:expr) -> $msg:pat => $($rest:tt)*] $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@body [$($rest)*] (recv ($rx) $msg) $ops $default $names)
+    };
+    (@parse [send($tx:expr, $val:expr) -> $res:pat => $($rest:tt)*] $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@body [$($rest)*] (send ($tx) ($val) $res) $ops $default $names)
+    };
+    (@parse [default($timeout:expr) => $($rest:tt)*] $ops:tt () $names:tt) => {
+        $crate::select!(@body [$($rest)*] (timeout ($timeout)) $ops () $names)
+    };
+    (@parse [default => $($rest:tt)*] $ops:tt () $names:tt) => {
+        $crate::select!(@body [$($rest)*] (default) $ops () $names)
+    };
+
+    // split off the arm's body, which is a block (comma optional) or an expression
+    (@body [$body:block, $($rest:tt)*] $arm:tt $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@arm [$($rest)*] $arm ($body) $ops $default $names)
+    };
+    (@body [$body:block $($rest:tt)*] $arm:tt $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@arm [$($rest)*] $arm ($body) $ops $default $names)
+    };
+    (@body [$body:expr, $($rest:tt)*] $arm:tt $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@arm [$($rest)*] $arm ($body) $ops $default $names)
+    };
+    (@body [$body:expr] $arm:tt $ops:tt $default:tt $names:tt) => {
+        $crate::select!(@arm [] $arm ($body) $ops $default $names)
+    };
+
+    (@arm $rest:tt (timeout $timeout:tt) $body:tt $ops:tt () $names:tt) => {
+        $crate::select!(@parse $rest $ops (timeout $timeout $body) $names)
+    };
+    (@arm $rest:tt (default) $body:tt $ops:tt () $names:tt) => {
+        $crate::select!(@parse $rest $ops (default $body) $names)
+    };
+    // every operation gets an ident of its own from the list, for where its result (and the value
+    // it's sending) is kept
+    (@arm $rest:tt ($kind:ident $($args:tt)*) $body:tt [$($ops:tt)*] $default:tt [$name:ident $($names:ident)*]) => {
+        $crate::select!(@parse $rest [$($ops)* ($name $kind $($args)* $body)] $default [$($names)*])
+    };
+
+    (@emit [$(($name:ident $kind:ident $($args:tt)*))*] $default:tt) => {{
+        // (value waiting to be sent, result of the operation, index in the Select)
+        $( let mut $name = $crate::select!(@storage $kind); )*
+        let deadline = $crate::select!(@deadline $default);
+
+        loop {
+            let mut sel = $crate::s5_channels::select::Select::new();
+            $( $name.2 = $crate::select!(@register sel $kind $($args)*); )*
+
+            let picked = match $crate::select!(@ready sel deadline $default) {
+                Some(picked) => picked,
+                None => break,
+            };
+            drop(sel);
+
+            // try_recv / try_send can still fail if another thread got there first. Then it's
+            // back to waiting
+            $(
+                if picked == $name.2 {
+                    if $crate::select!(@attempt $name $kind $($args)*) {
+                        break;
+                    }
+                    continue;
+                }
+            )*
+            unreachable!("select picked an operation that doesn't exist");
+        }
+
+        $crate::select!(@dispatch [$(($name $kind $($args)*))*] $default)
+    }};
+
+    (@storage recv) => {
+        (::core::option::Option::<()>::None, ::core::option::Option::None, 0usize)
+    };
+    (@storage send) => {
+        (::core::option::Option::None, ::core::option::Option::None, 0usize)
+    };
+
+    (@deadline ()) => {
+        ::core::option::Option::<::std::time::Instant>::None
+    };
+    (@deadline (default $body:tt)) => {
+        ::core::option::Option::<::std::time::Instant>::None
+    };
+    (@deadline (timeout $timeout:tt $body:tt)) => {
+        ::std::time::Instant::now().checked_add($timeout)
+    };
+
+    (@register $sel:ident recv $rx:tt $msg:tt $body:tt) => {
+        $sel.recv(&$rx)
+    };
+    (@register $sel:ident send $tx:tt $val:tt $res:tt $body:tt) => {
+        $sel.send(&$tx)
+    };
+
+    (@ready $sel:ident $deadline:ident ()) => {
+        ::core::option::Option::Some($sel.ready())
+    };
+    (@ready $sel:ident $deadline:ident (default $body:tt)) => {
+        $sel.try_ready().ok()
+    };
+    (@ready $sel:ident $deadline:ident (timeout $timeout:tt $body:tt)) => {
+        match $deadline {
+            ::core::option::Option::Some(deadline) => $sel.ready_deadline(deadline).ok(),
+            ::core::option::Option::None => ::core::option::Option::Some($sel.ready()),
+        }
+    };
+
+    (@attempt $name:ident recv $rx:tt $msg:tt $body:tt) => {
+        match $rx.try_recv() {
+            ::core::result::Result::Ok(t) => {
+                $name.1 = ::core::option::Option::Some(::core::option::Option::Some(t));
+                true
+            }
+            ::core::result::Result::Err($crate::s5_channels::channels::TryRecvError::Disconnected) => {
+                $name.1 = ::core::option::Option::Some(::core::option::Option::None);
+                true
+            }
+            ::core::result::Result::Err($crate::s5_channels::channels::TryRecvError::Empty) => false,
+        }
+    };
+    (@attempt $name:ident send $tx:tt $val:tt $res:tt $body:tt) => {{
+        let t = match $name.0.take() {
+            ::core::option::Option::Some(t) => t,
+            ::core::option::Option::None => $val,
+        };
+        match $tx.try_send(t) {
+            ::core::result::Result::Ok(()) => {
+                $name.1 = ::core::option::Option::Some(::core::result::Result::Ok(()));
+                true
+            }
+            ::core::result::Result::Err($crate::s5_channels::channels::TrySendError::Disconnected(t)) => {
+                $name.1 = ::core::option::Option::Some(::core::result::Result::Err(
+                    $crate::s5_channels::channels::SendError(t),
+                ));
+                true
+            }
+            ::core::result::Result::Err($crate::s5_channels::channels::TrySendError::Full(t)) => {
+                // hang on to it for the next try
+                $name.0 = ::core::option::Option::Some(t);
+                false
+            }
+        }
+    }};
+
+    // the arm bodies run outside the loop, so a break or continue in them is about the caller's
+    // loop, not ours
+    (@dispatch [($name:ident recv $rx:tt $msg:tt $body:tt) $($rest:tt)*] $default:tt) => {
+        if let ::core::option::Option::Some($msg) = $name.1 {
+            $body
+        } else {
+            $crate::select!(@dispatch [$($rest)*] $default)
+        }
+    };
+    (@dispatch [($name:ident send $tx:tt $val:tt $res:tt $body:tt) $($rest:tt)*] $default:tt) => {
+        if let ::core::option::Option::Some($res) = $name.1 {
+            $body
+        } else {
+            $crate::select!(@dispatch [$($rest)*] $default)
+        }
+    };
+    (@dispatch [] ()) => {
+        unreachable!("select finished without running an operation")
+    };
+    (@dispatch [] (default $body:tt)) => {
+        $body
+    };
+    (@dispatch [] (timeout $timeout:tt $body:tt)) => {
+        $body
+    };
+
+    ($($arms:tt)*) => {
+        $crate::select!(@parse [$($arms)*] [] () [
+            op0 op1 op2 op3 op4 op5 op6 op7 op8 op9 op10 op11 op12 op13 op14 op15
+            op16 op17 op18 op19 op20 op21 op22 op23 op24 op25 op26 op27 op28 op29 op30 op31
+        ])
+    };
+}
+
+pub fn tests() {
+    println!("Testing select");
+
+    let (mut tx1, mut rx1) = super::channels::channel::<i32>();
+    let (mut tx2, mut rx2) = super::channels::channel::<&str>();
+    tx2.send("hi").unwrap();
+
+    let got = select! {
+        recv(rx1) -> n => format!("{:?}", n),
+        recv(rx2) -> s => s.unwrap().to_string(),
+    };
+    assert_eq!(got, "hi");
+
+    let nothing = select! {
+        recv(rx1) -> n => false,
+        default => true,
+    };
+    assert!(nothing);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::channels::{channel, sync_channel, SendError};
+    use super::*;
+    use std::thread::{sleep, spawn};
+
+    #[test]
+    fn picks_whichever_is_ready() {
+        let (mut tx1, mut rx1) = channel::<i32>();
+        let (mut tx2, mut rx2) = channel::<i32>();
+
+        let sender = spawn(move || {
+            sleep(Duration::from_millis(50));
+            tx2.send(2).unwrap();
+            tx2
+        });
+
+        let mut sel = Select::new();
+        let one = sel.recv(&rx1);
+        let two = sel.recv(&rx2);
+        assert_eq!(sel.try_ready(), Err(TryReadyError));
+        assert_eq!(sel.ready(), two);
+
+        assert_eq!(rx2.try_recv(), Ok(2));
+        drop(sender.join().unwrap());
+
+        // a receive on a channel without senders is ready too, it won't block
+        let mut sel = Select::new();
+        sel.recv(&rx2);
+        assert_eq!(sel.ready_timeout(Duration::from_secs(5)), Ok(0));
+    }
+
+    #[test]
+    fn fair_when_several_are_ready() {
+        let (mut tx1, mut rx1) = channel();
+        let (mut tx2, mut rx2) = channel();
+        for _ in 0..200 {
+            tx1.send(1).unwrap();
+            tx2.send(2).unwrap();
+        }
+
+        let mut counts = [0; 3];
+        for _ in 0..200 {
+            let n = select! {
+                recv(rx1) -> n => n.unwrap(),
+                recv(rx2) -> n => n.unwrap(),
+            };
+            counts[n] += 1;
+        }
+
+        // always picking the first one would be 200 / 0
+        assert!(counts[1] > 50 && counts[2] > 50, "{:?}", counts);
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        let (mut tx, mut rx) = sync_channel(1);
+        tx.send(0).unwrap();
+
+        let receiver = spawn(move || {
+            sleep(Duration::from_millis(50));
+            let first = rx.receive();
+            (first, rx.receive())
+        });
+
+        let res = select! {
+            send(tx, 1) -> res => res,
+        };
+        assert_eq!(res, Ok(()));
+        assert_eq!(receiver.join().unwrap(), (Some(0), Some(1)));
+
+        // the receiver is gone now, the value comes back
+        let res = select! {
+            send(tx, 2) -> res => res,
+        };
+        assert_eq!(res, Err(SendError(2)));
+    }
+
+    #[test]
+    fn default_and_timeout() {
+        let (mut tx, mut rx) = channel::<i32>();
+
+        let start = Instant::now();
+        let timed_out = select! {
+            recv(rx) -> n => false,
+            default(Duration::from_millis(50)) => true,
+        };
+        assert!(timed_out);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let sender = spawn(move || {
+            sleep(Duration::from_millis(20));
+            tx.send(7).unwrap();
+        });
+
+        let got = select! {
+            recv(rx) -> n => n,
+            default(Duration::from_secs(5)) => None,
+        };
+        assert_eq!(got, Some(7));
+        sender.join().unwrap();
+
+        // all the senders are gone: that's ready, with None
+        let got = select! {
+            recv(rx) -> n => n,
+            default => Some(0),
+        };
+        assert_eq!(got, None);
+    }
+
+    #[test]
+    fn arm_bodies_can_break_out_of_the_callers_loop() {
+        let (mut tx, mut rx) = channel();
+        for i in 0..10 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        let mut sum = 0;
+        loop {
+            select! {
+                recv(rx) -> n => match n {
+                    Some(n) => sum += n,
+                    None => break,
+                },
+            }
+        }
+        assert_eq!(sum, 45);
+    }
+}
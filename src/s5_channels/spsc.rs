@@ -0,0 +1,602 @@
+// A ring buffer for exactly one producer and one consumer. No locks, no CAS loops: every operation
+// is a fixed number of loads and stores, so neither side ever waits on the other (wait-free). It's
+// also what makes it usable between two processes: all the state lives in one block of memory,
+// indices rather than pointers, and that block can be a shared mapping.
+//
+//   [ tail | pad ][ head | pad ][ capacity, slot size ][ slot 0 | slot 1 | ... | slot n-1 ]
+//
+// tail is the next slot the producer will fill, head the next one the consumer will read. Both
+// only ever go up (wrapping at usize::MAX), and the slot is the index mod the capacity, which is a
+// power of two so that's a mask. tail - head is how many items are in there.
+//
+// Each index is written by one side only, and each gets a cache line to itself. If they shared one,
+// every store the producer does to tail would take the line away from the consumer storing head,
+// and the other way around, even though they never touch each other's index.
+//
+// The other thing that keeps the two sides apart is batching. The producer keeps its own copy of
+// tail and only stores it into the shared one on publish(), so a hundred pushes cost one store the
+// consumer has to see. It also remembers the last head it loaded and only loads it again once the
+// ring looks full. The consumer does the same the other way around with release().
+//
+// Items are read and written in place, see write_slot and read_slot, which is why T: Copy: there's
+// no drop for items that get overwritten or are left behind. For a ring shared between processes T
+// has to be Pod as well. What's in the slots was put there by another process, and we only have
+// its word for it that those bytes are a T, so any bytes have to be. That rules out bool, char,
+// enums and references (which wouldn't mean anything on the other side anyway).
+//
+// There's no blocking and no disconnection: a full push or an empty pop just says so, and the
+// caller decides whether to spin, yield or do something else.
+
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::alloc::{self, Layout};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Plain old data: a type for which any bytes of the right size are a valid value
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes has to be a valid `Self`
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! pod {
+    ($($t:ty),*) => {
+        // SAFETY: numbers, any bits are some number
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+// SAFETY: an array has no padding between its items, so its bytes are just theirs
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// 64 bytes is a cache line on about everything. Padding to 128 would also keep the prefetcher on
+// x86 from pulling in the neighbouring line, but that's not worth doubling the header for here
+#[repr(C, align(64))]
+struct CachePadded<T>(T);
+
+#[repr(C)]
+struct Header {
+    // only the producer stores to this
+    tail: CachePadded<AtomicUsize>,
+    // only the consumer stores to this
+    head: CachePadded<AtomicUsize>,
+    // set once when the ring is created. slot_size is there so that a process opening someone
+    // else's ring can tell it's for a different T
+    capacity: usize,
+    slot_size: usize,
+}
+
+// The header, with the slots after it
+fn layout<T>(capacity: usize) -> (Layout, usize) {
+    let (layout, offset) = Layout::new::<Header>()
+        .extend(Layout::array::<T>(capacity).expect("ring too big"))
+        .expect("ring too big");
+    (layout.pad_to_align(), offset)
+}
+
+enum Memory {
+    Heap(Layout),
+    // the length of the mapping
+    Mapped(usize),
+}
+
+// The memory the ring lives in, shared by the two ends in this process and freed with the last one
+struct Region {
+    ptr: NonNull<u8>,
+    memory: Memory,
+}
+
+// SAFETY: it's just memory, what's in it is only touched through atomics or by the one side that
+// owns that slot at the time
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        match self.memory {
+            // SAFETY: allocated with this layout in ring()
+            Memory::Heap(layout) => unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) },
+            // SAFETY: mapped with this length in map()
+            Memory::Mapped(len) => unsafe {
+                libc::munmap(self.ptr.as_ptr().cast(), len);
+            },
+        }
+    }
+}
+
+// What both ends need to find their way around the ring
+struct Ring<T> {
+    region: MyArc<Region>,
+    slots: *mut T,
+    mask: usize,
+    _item: PhantomData<T>,
+}
+
+impl<T> Ring<T> {
+    // SAFETY: region has to start with an initialized Header for this T, followed by the slots
+    unsafe fn new(region: MyArc<Region>) -> Self {
+        let header = &*region.ptr.as_ptr().cast::<Header>();
+        let (_, offset) = layout::<T>(header.capacity);
+
+        Ring {
+            slots: region.ptr.as_ptr().add(offset).cast(),
+            mask: header.capacity - 1,
+            region,
+            _item: PhantomData,
+        }
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY: see new
+        unsafe { &*self.region.ptr.as_ptr().cast::<Header>() }
+    }
+
+    fn tail(&self) -> &AtomicUsize {
+        &self.header().tail.0
+    }
+
+    fn head(&self) -> &AtomicUsize {
+        &self.header().head.0
+    }
+
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        // SAFETY: masked, so it's one of the slots
+        unsafe { self.slots.add(index & self.mask) }
+    }
+}
+
+pub struct Producer<T> {
+    ring: Ring<T>,
+    // ours, including what hasn't been published yet
+    tail: usize,
+    // the last head we loaded. The consumer can only have moved on since, so if there's room
+    // according to this there's room for real
+    head: usize,
+}
+
+pub struct Consumer<T> {
+    ring: Ring<T>,
+    // ours, including what hasn't been released yet
+    head: usize,
+    // the last tail we loaded
+    tail: usize,
+}
+
+// SAFETY: each end is the only one touching its side of the ring, and T: Send is what it takes to
+// hand items over to the other end
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl<T: Copy> Producer<T> {
+    fn new(ring: Ring<T>) -> Self {
+        let tail = ring.tail().load(Ordering::Relaxed);
+        let head = ring.head().load(Ordering::Acquire);
+        Producer { ring, tail, head }
+    }
+
+    /// The next free slot, to be written in place. None if the ring is full. It only counts as
+    /// written once it's committed, and the consumer only sees it once it's published
+    pub fn write_slot(&mut self) -> Option<&mut MaybeUninit<T>> {
+        if self.tail.wrapping_sub(self.head) == self.ring.capacity() {
+            // full as far as we knew, see if the consumer has made room since
+            self.head = self.ring.head().load(Ordering::Acquire);
+
+            if self.tail.wrapping_sub(self.head) == self.ring.capacity() {
+                return None;
+            }
+        }
+
+        // SAFETY: the slot is between head and head + capacity, so the consumer is done with it
+        // and won't look at it again until we publish it
+        Some(unsafe { &mut *self.ring.slot(self.tail).cast() })
+    }
+
+    /// Count the slot from write_slot as written
+    ///
+    /// # Safety
+    ///
+    /// write_slot has to have returned Some since the last commit, and the slot has to be
+    /// initialized
+    pub unsafe fn commit(&mut self) {
+        debug_assert!(self.tail.wrapping_sub(self.head) < self.ring.capacity());
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    /// Hands the item back if the ring is full. Not visible to the consumer until publish
+    pub fn push(&mut self, t: T) -> Result<(), T> {
+        match self.write_slot() {
+            Some(slot) => {
+                slot.write(t);
+                // SAFETY: we just got the slot, and wrote it
+                unsafe { self.commit() };
+                Ok(())
+            }
+            None => Err(t),
+        }
+    }
+
+    /// Make everything committed so far visible to the consumer, with one store
+    pub fn publish(&mut self) {
+        // Release: the writes to the slots happen before the consumer can see the new tail
+        self.ring.tail().store(self.tail, Ordering::Release);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        // whatever was committed goes out, rather than being lost
+        self.ring.tail().store(self.tail, Ordering::Release);
+    }
+}
+
+impl<T: Copy> Consumer<T> {
+    fn new(ring: Ring<T>) -> Self {
+        let head = ring.head().load(Ordering::Relaxed);
+        let tail = ring.tail().load(Ordering::Acquire);
+        Consumer { ring, head, tail }
+    }
+
+    /// The next published item, read in place. None if there isn't one. Call advance to move on
+    /// to the one after it
+    pub fn read_slot(&mut self) -> Option<&T> {
+        if self.head == self.tail {
+            // empty as far as we knew, see if the producer has published more since
+            self.tail = self.ring.tail().load(Ordering::Acquire);
+
+            if self.head == self.tail {
+                return None;
+            }
+        }
+
+        // SAFETY: between head and tail, so the producer has written and published it, and won't
+        // touch it again until we release it
+        Some(unsafe { &*self.ring.slot(self.head) })
+    }
+
+    /// Done with the slot from read_slot. The producer can't reuse it until release
+    pub fn advance(&mut self) {
+        assert!(self.head != self.tail, "advance without a slot to read");
+        self.head = self.head.wrapping_add(1);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        let t = *self.read_slot()?;
+        self.advance();
+        Some(t)
+    }
+
+    /// Hand every slot we've advanced past back to the producer, with one store
+    pub fn release(&mut self) {
+        // Release: we're done reading the slots before the producer can see it can overwrite them
+        self.ring.head().store(self.head, Ordering::Release);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        self.ring.head().store(self.head, Ordering::Release);
+    }
+}
+
+fn check_capacity<T>(capacity: usize) {
+    assert!(
+        capacity.is_power_of_two(),
+        "the capacity has to be a power of two"
+    );
+    assert!(mem::size_of::<T>() > 0, "zero sized items need no ring");
+}
+
+// SAFETY: ptr has to point to zeroed memory laid out for a ring of T with this capacity
+unsafe fn init_header<T>(ptr: NonNull<u8>, capacity: usize) {
+    let header = ptr.as_ptr().cast::<Header>();
+    // head and tail are 0, which zeroed memory already is
+    (*header).capacity = capacity;
+    (*header).slot_size = mem::size_of::<T>();
+}
+
+/// A ring in this process's memory, for two threads. `capacity` has to be a power of two
+pub fn ring<T: Copy>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    check_capacity::<T>(capacity);
+
+    let (layout, _) = layout::<T>(capacity);
+    // SAFETY: the layout has the Header in it, so it isn't zero sized
+    let ptr = unsafe { alloc::alloc_zeroed(layout) };
+    let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+    // SAFETY: just allocated it, zeroed, with the layout for this ring
+    unsafe { init_header::<T>(ptr, capacity) };
+
+    let region = MyArc::new(Region {
+        ptr,
+        memory: Memory::Heap(layout),
+    });
+
+    // SAFETY: the header is initialized
+    unsafe {
+        (
+            Producer::new(Ring::new(region.clone())),
+            Consumer::new(Ring::new(region)),
+        )
+    }
+}
+
+/// A ring in a memfd, which another process can map as well: hand it the fd (over an
+/// ipc::Sender, or by forking) and have it open the ring from there. Then one process takes the
+/// producer, the other the consumer. Nothing here can tell whether the other process took the same
+/// end, which is why taking one is unsafe
+pub struct SharedRing<T> {
+    region: MyArc<Region>,
+    fd: OwnedFd,
+    _item: PhantomData<T>,
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+// map all of fd, shared, so that what we write is what the other process sees
+fn map(fd: BorrowedFd<'_>, len: usize) -> io::Result<MyArc<Region>> {
+    // SAFETY: a fresh mapping, the kernel picks where
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(MyArc::new(Region {
+        // mmap never returns null on success
+        ptr: NonNull::new(ptr.cast()).unwrap(),
+        memory: Memory::Mapped(len),
+    }))
+}
+
+impl<T: Pod> SharedRing<T> {
+    /// A new, empty ring. `capacity` has to be a power of two
+    pub fn create(capacity: usize) -> io::Result<Self> {
+        check_capacity::<T>(capacity);
+        let (layout, _) = layout::<T>(capacity);
+        // mmap hands out page aligned memory, anything T could need
+        assert!(layout.align() <= 4096, "items too aligned for a mapping");
+
+        // SAFETY: the name is a valid C string, and a new fd is ours to own
+        let fd = unsafe {
+            let fd = cvt(libc::memfd_create(c"spsc ring".as_ptr(), libc::MFD_CLOEXEC))?;
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        // a new memfd is empty, this makes it the size of the ring, zeroed
+        // SAFETY: fd is a memfd
+        cvt(unsafe { libc::ftruncate(fd.as_raw_fd(), layout.size() as libc::off_t) })?;
+
+        let region = map(fd.as_fd(), layout.size())?;
+        // SAFETY: mapped with the layout for this ring, and a fresh memfd is all zeroes
+        unsafe { init_header::<T>(region.ptr, capacity) };
+
+        Ok(SharedRing {
+            region,
+            fd,
+            _item: PhantomData,
+        })
+    }
+
+    /// Map a ring someone else created. Fails if its items aren't the size of a T. They don't have
+    /// to be T's otherwise, since T is Pod any item of the right size reads as some T
+    ///
+    /// # Safety
+    ///
+    /// `fd` has to be a ring made by `SharedRing::create`, and it can only ever be written to
+    /// through the producers and consumers of SharedRings
+    pub unsafe fn open(fd: OwnedFd) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        // SAFETY: an all zeroes stat is a valid one to be filled in
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        // SAFETY: stat is valid to write to
+        cvt(unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) })?;
+        let len = stat.st_size as usize;
+
+        if len < mem::size_of::<Header>() {
+            return Err(invalid("too small for a ring"));
+        }
+
+        let region = map(fd.as_fd(), len)?;
+        // SAFETY: at least a Header's worth of it is mapped. The capacity and slot size don't
+        // change after create, whatever the other process is doing
+        let header = unsafe { &*region.ptr.as_ptr().cast::<Header>() };
+
+        if header.slot_size != mem::size_of::<T>() {
+            return Err(invalid("ring of a different item type"));
+        }
+        if !header.capacity.is_power_of_two() || layout::<T>(header.capacity).0.size() > len {
+            return Err(invalid("not a valid ring"));
+        }
+
+        Ok(SharedRing {
+            region,
+            fd,
+            _item: PhantomData,
+        })
+    }
+
+    /// To send to the other process
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    /// # Safety
+    ///
+    /// There can only be one producer for a ring at a time, in all the processes that have it
+    /// mapped. Every SharedRing for it, in this process or another, has to take the consumer or
+    /// nothing while this one is around
+    pub unsafe fn producer(self) -> Producer<T> {
+        // SAFETY: the header was checked, or written by us
+        Producer::new(unsafe { Ring::new(self.region) })
+    }
+
+    /// # Safety
+    ///
+    /// Same as for producer: only one consumer at a time, in all the processes that have the
+    /// ring mapped
+    pub unsafe fn consumer(self) -> Consumer<T> {
+        // SAFETY: the header was checked, or written by us
+        Consumer::new(unsafe { Ring::new(self.region) })
+    }
+}
+
+pub fn tests() {
+    println!("Testing spsc ring");
+
+    let (mut tx, mut rx) = ring(4);
+    for i in 0..4 {
+        tx.push(i).unwrap();
+    }
+    assert_eq!(tx.push(4), Err(4));
+
+    // nothing is visible until it's published
+    assert_eq!(rx.pop(), None);
+    tx.publish();
+    assert_eq!(rx.pop(), Some(0));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::ipc::tests::{is_child, spawn_child, wait_child};
+    use super::*;
+    use std::thread::{spawn, yield_now};
+
+    #[test]
+    fn indices_get_their_own_cache_lines() {
+        assert_eq!(mem::offset_of!(Header, tail), 0);
+        assert_eq!(mem::offset_of!(Header, head), 64);
+        assert!(mem::offset_of!(Header, capacity) >= 128);
+    }
+
+    #[test]
+    fn batches_and_wraps_around() {
+        let (mut tx, mut rx) = ring::<u32>(4);
+
+        for round in 0..10 {
+            let slot = tx.write_slot().unwrap();
+            slot.write(round);
+            // SAFETY: written just now
+            unsafe { tx.commit() };
+            tx.push(round + 100).unwrap();
+            tx.publish();
+
+            assert_eq!(rx.read_slot(), Some(&round));
+            rx.advance();
+            assert_eq!(rx.pop(), Some(round + 100));
+            assert_eq!(rx.pop(), None);
+
+            // until the consumer releases them the producer can't have those slots back, and the
+            // ring fills up after 4 - 2
+            tx.push(0).unwrap();
+            tx.push(0).unwrap();
+            assert_eq!(tx.push(0), Err(0));
+            rx.release();
+            tx.publish();
+            assert_eq!((rx.pop(), rx.pop()), (Some(0), Some(0)));
+            rx.release();
+        }
+    }
+
+    // these yield rather than spin while they wait, the other side might need this core to make
+    // progress
+    fn produce(mut tx: Producer<u64>, n: u64) {
+        for i in 0..n {
+            while tx.push(i).is_err() {
+                tx.publish();
+                yield_now();
+            }
+            if i % 64 == 63 {
+                tx.publish();
+            }
+        }
+        tx.publish();
+    }
+
+    fn consume(mut rx: Consumer<u64>, n: u64) {
+        let mut expected = 0;
+        while expected < n {
+            match rx.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None => {
+                    rx.release();
+                    yield_now();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn between_two_threads() {
+        let (tx, rx) = ring(64);
+        let producer = spawn(move || produce(tx, 100_000));
+        consume(rx, 100_000);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn between_two_processes() {
+        if is_child() {
+            // a mapping of its own, of the memfd the parent gave us as stdin
+            let fd = io::stdin().as_fd().try_clone_to_owned().unwrap();
+            // SAFETY: the parent made it, and only takes the consumer
+            let tx = unsafe { SharedRing::<u64>::open(fd).unwrap().producer() };
+            produce(tx, 100_000);
+            return;
+        }
+
+        let ring = SharedRing::<u64>::create(256).unwrap();
+        let child = spawn_child(
+            "s5_channels::spsc::tests::between_two_processes",
+            ring.fd().try_clone_to_owned().unwrap(),
+        );
+
+        // SAFETY: the child takes the producer
+        consume(unsafe { ring.consumer() }, 100_000);
+        wait_child(child);
+    }
+
+    #[test]
+    fn open_checks_the_item_type() {
+        let ring = SharedRing::<u64>::create(8).unwrap();
+        let fd = ring.fd().try_clone_to_owned().unwrap();
+        // SAFETY: made by create, the item size is what's wrong
+        assert!(unsafe { SharedRing::<u32>::open(fd) }.is_err());
+    }
+}
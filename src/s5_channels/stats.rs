@@ -0,0 +1,306 @@
+// Numbers about a channel, for when a pipeline stalls and it's not clear where: a queue that keeps
+// growing has a slow receiver, a receiver that spends all its time parked has a slow sender.
+//
+// It's opt-in, per channel: Sender::instrument or Receiver::instrument gives the channel a name and
+// starts counting. Channels that aren't instrumented don't pay for any of it, not even a call to
+// Instant::now around their waits.
+//
+// The counters are atomics, even though the channel only ever updates them with its lock held. That
+// way a snapshot doesn't need the channel's lock, which is what lets the registry go through every
+// channel without getting in their way (or having to know their T).
+//
+// An instrumented channel is in the registry for as long as either end of it is around. all() lists
+// them.
+
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A snapshot of an instrumented channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub name: String,
+    /// Items in the channel right now. Ones a receiver took into its own buffer count as received
+    pub depth: usize,
+    /// The most there's ever been
+    pub high_water_mark: usize,
+    /// Every send that went through, including the ones that found a channel with an overflow
+    /// policy full, so `sent - received - dropped - merged` is the depth
+    pub sent: u64,
+    pub received: u64,
+    /// Items an overflow policy dropped, the oldest one in the channel or the one being sent
+    pub dropped: u64,
+    /// Items an overflow policy merged into the newest one
+    pub merged: u64,
+    /// How long receivers spent parked waiting for something to receive, all of them together
+    pub recv_wait: Duration,
+    /// The longest a receiver was parked in one go
+    pub max_recv_wait: Duration,
+    /// How long senders spent blocked on a full channel, all of them together
+    pub send_wait: Duration,
+}
+
+pub(super) struct Counters {
+    name: String,
+    depth: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    sent: AtomicU64,
+    received: AtomicU64,
+    dropped: AtomicU64,
+    merged: AtomicU64,
+    // in ns
+    recv_wait: AtomicU64,
+    max_recv_wait: AtomicU64,
+    send_wait: AtomicU64,
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_nanos().min(u64::MAX as u128) as u64
+}
+
+impl Counters {
+    // Relaxed all the way: every update happens under the channel's lock anyway, and a snapshot is
+    // allowed to be a little out of date
+    pub(super) fn sent(&self, depth: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.depth(depth);
+    }
+
+    pub(super) fn received(&self, n: usize, depth: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+        self.depth(depth);
+    }
+
+    // on top of sent(), for a send that overflowed
+    pub(super) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn merged(&self) {
+        self.merged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // undoes received(), for items a receiver puts back
+    pub(super) fn returned(&self, n: usize, depth: usize) {
+        self.received.fetch_sub(n as u64, Ordering::Relaxed);
+        self.depth(depth);
+    }
+
+    // undoes sent(), for an item a sender takes back
+    pub(super) fn unsent(&self, depth: usize) {
+        self.sent.fetch_sub(1, Ordering::Relaxed);
+        self.depth(depth);
+    }
+
+    pub(super) fn depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.high_water_mark.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub(super) fn recv_waited(&self, since: Instant) {
+        let waited = nanos(since.elapsed());
+        self.recv_wait.fetch_add(waited, Ordering::Relaxed);
+        self.max_recv_wait.fetch_max(waited, Ordering::Relaxed);
+    }
+
+    pub(super) fn send_waited(&self, since: Instant) {
+        let waited = nanos(since.elapsed());
+        self.send_wait.fetch_add(waited, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            name: self.name.clone(),
+            depth: self.depth.load(Ordering::Relaxed),
+            high_water_mark: self.high_water_mark.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            merged: self.merged.load(Ordering::Relaxed),
+            recv_wait: Duration::from_nanos(self.recv_wait.load(Ordering::Relaxed)),
+            max_recv_wait: Duration::from_nanos(self.max_recv_wait.load(Ordering::Relaxed)),
+            send_wait: Duration::from_nanos(self.send_wait.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+static REGISTRY: Mutex<Vec<MyArc<Counters>>> = Mutex::new(Vec::new());
+
+// A channel's counters, and its place in the registry until it's dropped
+pub(super) struct Registration {
+    counters: MyArc<Counters>,
+}
+
+impl Registration {
+    pub(super) fn new(name: String, depth: usize) -> Self {
+        let counters = MyArc::new(Counters {
+            name,
+            depth: AtomicUsize::new(depth),
+            high_water_mark: AtomicUsize::new(depth),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            merged: AtomicU64::new(0),
+            recv_wait: AtomicU64::new(0),
+            max_recv_wait: AtomicU64::new(0),
+            send_wait: AtomicU64::new(0),
+        });
+
+        REGISTRY.lock().unwrap().push(counters.clone());
+        Registration { counters }
+    }
+}
+
+impl std::ops::Deref for Registration {
+    type Target = Counters;
+
+    fn deref(&self) -> &Self::Target {
+        &self.counters
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        REGISTRY
+            .lock()
+            .unwrap()
+            .retain(|counters| !MyArc::ptr_eq(counters, &self.counters));
+    }
+}
+
+/// Every instrumented channel that's still around, by name. Names don't have to be unique
+pub fn all() -> Vec<ChannelStats> {
+    let mut stats: Vec<_> = REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|counters| counters.snapshot())
+        .collect();
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    stats
+}
+
+pub fn tests() {
+    use super::channels::sync_channel;
+
+    println!("Testing channel stats");
+
+    let (mut tx, mut rx) = sync_channel(8);
+    tx.instrument("demo");
+    tx.send(1).unwrap();
+    tx.send(2).unwrap();
+    rx.receive().unwrap();
+
+    let stats = rx.stats().unwrap();
+    assert_eq!((stats.sent, stats.received, stats.depth), (2, 1, 1));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::channels::{bounded_channel, channel, sync_channel, OverflowPolicy};
+    use super::*;
+    use std::thread::{sleep, spawn};
+
+    // the registry is shared with every other test running at the same time, so these only look
+    // at their own channels
+    fn registered(name: &str) -> Vec<ChannelStats> {
+        all().into_iter().filter(|s| s.name == name).collect()
+    }
+
+    #[test]
+    fn counts_items_and_depth() {
+        let (mut tx, mut rx) = channel();
+        assert_eq!(tx.stats(), None);
+
+        // anything before this isn't counted, but the depth is right from the start
+        tx.send(0).unwrap();
+        rx.instrument("stats::counts_items_and_depth");
+
+        for i in 1..=4 {
+            tx.send(i).unwrap();
+        }
+        assert_eq!(rx.receive(), Some(0));
+        assert_eq!(rx.receive(), Some(1));
+
+        let stats = tx.stats().unwrap();
+        // receive took 0 to 4 in one batch, they're not in the channel anymore
+        assert_eq!((stats.sent, stats.received), (4, 5));
+        assert_eq!((stats.depth, stats.high_water_mark), (0, 5));
+        assert_eq!(registered("stats::counts_items_and_depth"), [stats]);
+
+        // gone from the registry with the channel
+        drop(tx);
+        drop(rx);
+        assert!(registered("stats::counts_items_and_depth").is_empty());
+    }
+
+    #[test]
+    fn counts_what_overflow_policies_drop_and_merge() {
+        fn add(newest: &mut i32, t: i32) {
+            *newest += t;
+        }
+
+        let policies = [
+            (OverflowPolicy::DropOldest, vec![1, 2], (1, 0)),
+            (OverflowPolicy::DropNewest, vec![0, 1], (1, 0)),
+            (OverflowPolicy::Coalesce(add), vec![0, 3], (0, 1)),
+            (OverflowPolicy::Block, vec![0, 1], (0, 0)),
+        ];
+
+        for (policy, items, (dropped, merged)) in policies {
+            let (mut tx, mut rx) = bounded_channel(2, policy);
+            rx.instrument("stats::counts_what_overflow_policies_drop_and_merge");
+
+            // one too many for anything but Block
+            let sends = if dropped + merged > 0 { 3 } else { 2 };
+            for i in 0..sends {
+                tx.send(i).unwrap();
+            }
+
+            let stats = tx.stats().unwrap();
+            assert_eq!(
+                (stats.dropped, stats.merged),
+                (dropped, merged),
+                "{:?}",
+                policy
+            );
+            assert_eq!(
+                stats.sent - stats.received - stats.dropped - stats.merged,
+                2
+            );
+            assert_eq!(stats.depth, 2);
+
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), items, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn times_waits_on_both_sides() {
+        let (mut tx, mut rx) = sync_channel(1);
+        tx.instrument("stats::times_waits_on_both_sides");
+
+        let receiver = spawn(move || {
+            // parked until the first send
+            let first = rx.receive();
+            sleep(Duration::from_millis(50));
+            // the sender is blocked on the full channel in the meantime
+            (first, rx.receive(), rx.receive(), rx)
+        });
+
+        sleep(Duration::from_millis(50));
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        let (a, b, c, rx) = receiver.join().unwrap();
+        assert_eq!((a, b, c), (Some(0), Some(1), Some(2)));
+
+        let stats = rx.stats().unwrap();
+        assert!(stats.max_recv_wait >= Duration::from_millis(40));
+        assert!(stats.recv_wait >= stats.max_recv_wait);
+        assert!(stats.send_wait >= Duration::from_millis(40));
+        assert_eq!(stats.high_water_mark, 1);
+    }
+}
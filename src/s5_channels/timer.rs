@@ -0,0 +1,401 @@
+// Timers as channels: after(d) is a Receiver that gets the time once d has passed, tick(d) one that
+// gets it every d. They're regular channels::Receiver's, so they work with receive, recv_timeout,
+// Select and select!.
+//
+// All the timers are run by one background thread, which keeps them in a hierarchical timer wheel.
+// A wheel is 64 slots of 1ms each, and a timer goes into the slot of the millisecond it's due in,
+// so firing the next one is just looking at the next slot. That only covers the next 64ms though,
+// so there are more wheels on top, each with slots 64 times as wide as the one below:
+//
+//   level 0:  64 slots of 1ms        the next 64ms
+//   level 1:  64 slots of 64ms       the next ~4s
+//   level 2:  64 slots of ~4s        the next ~4.5min
+//   ...
+//   level 5:  64 slots of ~12.7days  the next ~2.2years
+//
+// and anything past the end of that goes on an overflow list, which is looked at again every time
+// the top level comes around.
+//
+// A timer goes into the lowest level that still reaches its deadline. Once the time reaches the
+// start of a slot on a higher level, everything in it gets put back in again (cascaded), and since
+// it's all due within that slot's width now, it ends up a level lower. Adding a timer and firing one
+// is constant time, however many timers there are.
+//
+// Dropping the receiver doesn't touch the wheel, the receiver doesn't know where its timer is. So
+// once a second the timer thread goes through every timer and drops the ones whose receiver is
+// gone. Otherwise a loop that selects on after(30s) every time around would leave a dead timer (and
+// its channel) behind every time, for 30s each. It also notices when it gets to one anyway, when it
+// fires (the send fails) or is cascaded.
+//
+// Each timer's channel has room for one item. A tick whose receiver hasn't taken the last one yet is
+// skipped, same as a ticker in Go, so a slow receiver doesn't pile up a backlog of ticks. Once an
+// after has fired its sender is dropped, so a receive after the one that got the time returns None.
+
+use super::channels::{sync_channel, Receiver, Sender, TrySendError};
+use crate::s4_smart_pointers_interior_mutability::once::MyOnceLock;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+// one full rotation of the top level, in ms, minus one
+const LAST_TICK: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+// how often cancelled timers are swept out, in ms
+const SWEEP: u64 = 1000;
+
+struct Entry {
+    // in ms since the driver started
+    deadline: u64,
+    // Some for a tick
+    period: Option<u64>,
+    tx: Sender<Instant>,
+}
+
+struct Wheel {
+    // in ms since the driver started. Everything up to here has been fired
+    now: u64,
+    levels: [[Vec<Entry>; SLOTS]; LEVELS],
+    // too far out for this rotation of the top level
+    overflow: Vec<Entry>,
+    next_sweep: u64,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Wheel {
+            now: 0,
+            levels: std::array::from_fn(|_| std::array::from_fn(|_| Vec::new())),
+            overflow: Vec::new(),
+            next_sweep: SWEEP,
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if entry.deadline <= self.now {
+            // due already, e.g. after(0), or cascaded right at its deadline
+            self.fire(entry);
+            return;
+        }
+
+        // the highest bit where the deadline and now differ says which level it goes on. Above
+        // that they're in the same slot of every higher level
+        let masked = (entry.deadline ^ self.now) | (SLOTS as u64 - 1);
+        let level = ((63 - masked.leading_zeros()) / SLOT_BITS) as usize;
+
+        if level >= LEVELS {
+            self.overflow.push(entry);
+            return;
+        }
+
+        let slot = (entry.deadline >> (level as u32 * SLOT_BITS)) as usize % SLOTS;
+        self.levels[level][slot].push(entry);
+    }
+
+    fn fire(&mut self, mut entry: Entry) {
+        let result = entry.tx.try_send(Instant::now());
+
+        let period = match (entry.period, result) {
+            // the receiver is gone, so is the timer
+            (_, Err(TrySendError::Disconnected(_))) => return,
+            // an after only fires once. Dropping its sender here is what closes the channel
+            (None, _) => return,
+            // sent, or the last tick hasn't been taken yet and this one is skipped
+            (Some(period), _) => period,
+        };
+
+        // if we're running late, skip the ticks we've missed instead of firing them all at once
+        let missed = (self.now - entry.deadline) / period;
+        entry.deadline += (missed + 1) * period;
+        self.insert(entry);
+    }
+
+    // The time of the next slot with something in it, and where it is (level LEVELS being the
+    // overflow list). Every level only has timers in the slots after the one that `now` is in, and
+    // a slot on a lower level always comes before any slot on a higher one, so the first one we find
+    // is the earliest
+    fn next_expiration(&self) -> Option<(u64, usize, usize)> {
+        for (level, slots) in self.levels.iter().enumerate() {
+            let shift = level as u32 * SLOT_BITS;
+            let current = (self.now >> shift) as usize % SLOTS;
+
+            if let Some(slot) = (current + 1..SLOTS).find(|&slot| !slots[slot].is_empty()) {
+                // the start of the current rotation of this level, plus the slot
+                let rotation = self.now & !((1 << (shift + SLOT_BITS)) - 1);
+                return Some((rotation + ((slot as u64) << shift), level, slot));
+            }
+        }
+
+        if !self.overflow.is_empty() {
+            // the start of the next rotation of the top level
+            return Some(((self.now | LAST_TICK) + 1, LEVELS, 0));
+        }
+
+        None
+    }
+
+    /// Fire every timer that's due at or before `until`
+    fn advance(&mut self, until: u64) {
+        while let Some((when, level, slot)) = self.next_expiration() {
+            if when > until {
+                break;
+            }
+
+            self.now = when;
+            let entries = match self.levels.get_mut(level) {
+                Some(slots) => std::mem::take(&mut slots[slot]),
+                None => std::mem::take(&mut self.overflow),
+            };
+
+            for entry in entries {
+                // cancelled since the last sweep, no point moving it down
+                if level > 0 && entry.tx.is_closed() {
+                    continue;
+                }
+
+                // on level 0 it's due now and fires, higher up it goes down a level or more
+                self.insert(entry);
+            }
+        }
+
+        // nothing is due in between, so we can skip right to it
+        self.now = self.now.max(until);
+
+        if self.now >= self.next_sweep {
+            self.sweep();
+            self.next_sweep = self.now + SWEEP;
+        }
+    }
+
+    // Drop every timer whose receiver is gone
+    fn sweep(&mut self) {
+        for entries in self.levels.iter_mut().flatten() {
+            entries.retain(|entry| !entry.tx.is_closed());
+        }
+        self.overflow.retain(|entry| !entry.tx.is_closed());
+    }
+
+    // When the thread has to be up next: the next expiration, or the next sweep if there's
+    // anything to sweep
+    fn next_wakeup(&self) -> Option<u64> {
+        let next = self.next_expiration().map(|(when, _, _)| when);
+
+        if self.len() == 0 {
+            return next;
+        }
+
+        Some(next.map_or(self.next_sweep, |when| when.min(self.next_sweep)))
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().flatten().map(Vec::len).sum::<usize>() + self.overflow.len()
+    }
+}
+
+struct Driver {
+    start: Instant,
+    wheel: Mutex<Wheel>,
+    // a new timer was added, the thread might need to wake up sooner than it planned to
+    added: Condvar,
+}
+
+static DRIVER: MyOnceLock<Driver> = MyOnceLock::new();
+
+fn driver() -> &'static Driver {
+    DRIVER.get_or_init(|| {
+        // the thread's own call to driver() waits for this closure to be done
+        thread::Builder::new()
+            .name("timer".into())
+            .spawn(|| run(driver()))
+            .expect("failed to spawn the timer thread");
+
+        Driver {
+            start: Instant::now(),
+            wheel: Mutex::new(Wheel::new()),
+            added: Condvar::new(),
+        }
+    })
+}
+
+fn ms(d: Duration) -> u64 {
+    // rounded up, a timer should never fire early. Duration::MAX is ~1.8e16ms, that fits
+    d.as_nanos().div_ceil(1_000_000) as u64
+}
+
+fn run(driver: &'static Driver) {
+    let mut wheel = driver.wheel.lock().unwrap();
+
+    loop {
+        // rounded down: everything due by now, not by the end of this millisecond
+        let elapsed = driver.start.elapsed().as_millis() as u64;
+        wheel.advance(elapsed);
+
+        wheel = match wheel.next_wakeup() {
+            None => driver.added.wait(wheel).unwrap(),
+            Some(when) => {
+                let timeout = (driver.start + Duration::from_millis(when))
+                    .saturating_duration_since(Instant::now());
+                driver.added.wait_timeout(wheel, timeout).unwrap().0
+            }
+        };
+    }
+}
+
+fn add(delay: Duration, period: Option<Duration>) -> Receiver<Instant> {
+    let driver = driver();
+    let (tx, rx) = sync_channel(1);
+
+    let entry = Entry {
+        deadline: ms(driver.start.elapsed()) + ms(delay),
+        period: period.map(ms),
+        tx,
+    };
+
+    driver.wheel.lock().unwrap().insert(entry);
+    driver.added.notify_one();
+
+    rx
+}
+
+/// A receiver that gets the time once, after `delay`. Drop it to cancel
+pub fn after(delay: Duration) -> Receiver<Instant> {
+    add(delay, None)
+}
+
+/// A receiver that gets the time every `period`, until it's dropped
+pub fn tick(period: Duration) -> Receiver<Instant> {
+    assert!(!period.is_zero(), "tick needs a period of at least 1ms");
+    add(period, Some(period))
+}
+
+pub fn tests() {
+    println!("Testing timers");
+
+    let start = Instant::now();
+    let mut timeout = after(Duration::from_millis(10));
+    assert!(timeout.receive().unwrap() >= start + Duration::from_millis(10));
+    assert_eq!(timeout.receive(), None);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::super::channels::{channel, TryRecvError};
+    use super::*;
+
+    fn entry(deadline: u64, period: Option<u64>) -> (Entry, Receiver<Instant>) {
+        let (tx, rx) = sync_channel(1);
+        (
+            Entry {
+                deadline,
+                period,
+                tx,
+            },
+            rx,
+        )
+    }
+
+    #[test]
+    fn wheel_fires_on_time_across_levels() {
+        let mut wheel = Wheel::new();
+        let deadlines = [1, 63, 64, 65, 4095, 4096, 5000, 300_000, LAST_TICK + 10];
+
+        let mut receivers: Vec<_> = deadlines
+            .iter()
+            .map(|&d| {
+                let (e, rx) = entry(d, None);
+                wheel.insert(e);
+                (d, rx)
+            })
+            .collect();
+
+        for (deadline, rx) in &mut receivers {
+            wheel.advance(*deadline - 1);
+            assert_eq!(rx.try_recv(), Err(TryRecvError::Empty), "{}", deadline);
+            wheel.advance(*deadline);
+            assert!(rx.try_recv().is_ok(), "{}", deadline);
+        }
+
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn wheel_drops_cancelled_timers() {
+        let mut wheel = Wheel::new();
+
+        let (near, rx_near) = entry(10, Some(10));
+        let (far, rx_far) = entry(100_000, None);
+        wheel.insert(near);
+        wheel.insert(far);
+        drop(rx_near);
+        drop(rx_far);
+        assert_eq!(wheel.len(), 2);
+
+        // the tick notices when it fires
+        wheel.advance(10);
+        assert_eq!(wheel.len(), 1);
+
+        // the far one at the next sweep, long before it would be cascaded
+        wheel.advance(SWEEP - 1);
+        assert_eq!(wheel.len(), 1);
+        wheel.advance(SWEEP);
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn wheel_skips_missed_ticks() {
+        let mut wheel = Wheel::new();
+        let (e, mut rx) = entry(10, Some(10));
+        wheel.insert(e);
+
+        // way late: it fires once, and the next one is at the next multiple of the period
+        wheel.advance(95);
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+
+        wheel.advance(99);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        wheel.advance(100);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn after_and_tick() {
+        let start = Instant::now();
+
+        let mut ticks = tick(Duration::from_millis(20));
+        for i in 1..=3 {
+            let at = ticks.receive().unwrap();
+            assert!(at >= start + Duration::from_millis(20 * i));
+        }
+
+        let mut timeout = after(Duration::from_millis(30));
+        assert!(timeout.receive().unwrap() >= start + Duration::from_millis(30));
+    }
+
+    #[test]
+    fn lots_of_timers_on_one_thread() {
+        let start = Instant::now();
+        let mut timers: Vec<_> = (0..1000)
+            .map(|i| (i % 100, after(Duration::from_millis(i % 100))))
+            .collect();
+
+        for (delay, rx) in &mut timers {
+            let at = rx.receive().unwrap();
+            assert!(at >= start + Duration::from_millis(*delay));
+        }
+    }
+
+    #[test]
+    fn works_with_select() {
+        let (tx, mut rx) = channel::<i32>();
+        let mut timeout = after(Duration::from_millis(30));
+
+        let timed_out = crate::select! {
+            recv(rx) -> _ => false,
+            recv(timeout) -> _ => true,
+        };
+        assert!(timed_out);
+        drop(tx);
+    }
+}
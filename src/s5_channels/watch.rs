@@ -0,0 +1,266 @@
+// A channel that only holds the latest value. Sending replaces it, receivers can look at it any
+// time, and changed() waits for the next send. For state that's being watched rather than a stream
+// of messages: a config, a "shutting down" flag, the current leader.
+//
+// Every send bumps a version, and each receiver remembers the version it saw last. That's all
+// changed() needs: return once the version is different from ours. A receiver that doesn't look
+// for a while just sees the latest value, everything in between is gone (that's the point).
+
+use super::channels::{SendError, Shared};
+use crate::s4_smart_pointers_interior_mutability::arc::MyArc;
+use std::sync::MutexGuard;
+
+struct State<T> {
+    value: T,
+    version: u64,
+    sender_alive: bool,
+    receivers: usize,
+}
+
+pub struct Sender<T> {
+    shared: MyArc<Shared<State<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: MyArc<Shared<State<T>>>,
+    // the version we've seen last
+    seen: u64,
+}
+
+/// Returned by `changed` once the sender is gone. The last value can still be borrowed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecvError;
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the sender has been dropped")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// A look at the current value. Holds the lock, so don't hang on to it: sends block until it's
+/// dropped
+pub struct Ref<'a, T> {
+    state: MutexGuard<'a, State<T>>,
+}
+
+impl<T> std::ops::Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state.value
+    }
+}
+
+impl<T> Sender<T> {
+    /// Replace the value and wake everyone waiting in `changed`. Fails if there are no receivers
+    /// left, handing the value back
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.inner.lock().unwrap();
+
+        if state.receivers == 0 {
+            return Err(SendError(t));
+        }
+
+        let old = std::mem::replace(&mut state.value, t);
+        state.version += 1;
+        drop(state);
+
+        // outside the lock, same as channels.rs: T's Drop could do anything, send on this channel
+        // included
+        drop(old);
+        self.shared.available.notify_all();
+        Ok(())
+    }
+
+    /// Change the value in place. Counts as a send, even if `f` doesn't actually change anything
+    pub fn send_modify(&self, f: impl FnOnce(&mut T)) {
+        let mut state = self.shared.inner.lock().unwrap();
+        f(&mut state.value);
+        state.version += 1;
+        drop(state);
+
+        self.shared.available.notify_all();
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            state: self.shared.inner.lock().unwrap(),
+        }
+    }
+
+    /// A new receiver, which considers the current value seen
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.inner.lock().unwrap();
+        state.receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            seen: state.version,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().sender_alive = false;
+        self.shared.available.notify_all();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The current value, without marking it as seen
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            state: self.shared.inner.lock().unwrap(),
+        }
+    }
+
+    /// The current value, and mark it as seen
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let state = self.shared.inner.lock().unwrap();
+        self.seen = state.version;
+        Ref { state }
+    }
+
+    /// Has there been a send since we last looked? Fails once the sender is gone
+    pub fn has_changed(&self) -> Result<bool, RecvError> {
+        let state = self.shared.inner.lock().unwrap();
+
+        if !state.sender_alive {
+            return Err(RecvError);
+        }
+
+        Ok(state.version != self.seen)
+    }
+
+    /// Wait for a value we haven't seen yet, and mark it as seen. Returns right away if there
+    /// already is one. Fails once the sender is gone
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        let mut state = self.shared.inner.lock().unwrap();
+
+        loop {
+            if state.version != self.seen {
+                self.seen = state.version;
+                return Ok(());
+            }
+
+            if !state.sender_alive {
+                return Err(RecvError);
+            }
+
+            state = self.shared.available.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    /// The clone has seen what this one has seen
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().receivers += 1;
+
+        Receiver {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().receivers -= 1;
+    }
+}
+
+/// A channel that starts out holding `initial`, which the receiver considers seen
+pub fn watch<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let state = State {
+        value: initial,
+        version: 0,
+        sender_alive: true,
+        receivers: 1,
+    };
+    let shared = MyArc::new(Shared::new(state));
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, seen: 0 },
+    )
+}
+
+pub fn tests() {
+    println!("Testing watch");
+
+    let (tx, mut rx) = watch("starting");
+    assert_eq!(rx.has_changed(), Ok(false));
+
+    tx.send("running").unwrap();
+    rx.changed().unwrap();
+    assert_eq!(*rx.borrow(), "running");
+
+    drop(tx);
+    assert_eq!(rx.changed(), Err(RecvError));
+    assert_eq!(*rx.borrow(), "running");
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    #[test]
+    fn changed_waits_for_a_send() {
+        let (tx, mut rx) = watch(0);
+
+        let watcher = spawn(move || {
+            let mut seen = vec![];
+            while rx.changed().is_ok() {
+                seen.push(*rx.borrow());
+            }
+            seen
+        });
+
+        sleep(Duration::from_millis(50));
+        tx.send(1).unwrap();
+        sleep(Duration::from_millis(50));
+        tx.send_modify(|v| *v += 1);
+        sleep(Duration::from_millis(50));
+        drop(tx);
+
+        assert_eq!(watcher.join().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn only_the_latest_value_is_kept() {
+        let (tx, mut rx) = watch(String::from("a"));
+        let mut other = rx.clone();
+
+        tx.send(String::from("b")).unwrap();
+        tx.send(String::from("c")).unwrap();
+
+        // two sends, one change: the receiver just sees where things are now
+        assert_eq!(rx.has_changed(), Ok(true));
+        assert_eq!(*rx.borrow_and_update(), "c");
+        assert_eq!(rx.has_changed(), Ok(false));
+
+        // every receiver keeps track of its own
+        assert_eq!(other.has_changed(), Ok(true));
+        other.changed().unwrap();
+        assert_eq!(*tx.borrow(), "c");
+
+        let late = tx.subscribe();
+        assert_eq!(late.has_changed(), Ok(false));
+
+        drop(rx);
+        drop(other);
+        drop(late);
+        assert_eq!(
+            tx.send(String::from("d")),
+            Err(SendError(String::from("d")))
+        );
+    }
+}
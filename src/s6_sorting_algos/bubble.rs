@@ -1,12 +1,10 @@
 use super::orst::Sorter;
+use std::cmp::Ordering;
 
 pub struct BubbleSort;
 
 impl<T> Sorter<T> for BubbleSort {
-    fn sort(&self, slice: &mut [T])
-    where
-        T: Ord,
-    {
+    fn sort_by(&self, slice: &mut [T], mut compare: impl FnMut(&T, &T) -> Ordering) {
         if slice.len() == 0 {
             return;
         }
@@ -18,7 +16,7 @@ impl<T> Sorter<T> for BubbleSort {
 
             // this will panic on an empty slice, but YOLO
             for i in 0..(slice.len() - 1) {
-                if slice[i] > slice[i + 1] {
+                if compare(&slice[i], &slice[i + 1]) == Ordering::Greater {
                     slice.swap(i, i + 1);
                     swapped = true;
                 }
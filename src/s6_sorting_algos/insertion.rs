@@ -1,19 +1,17 @@
 use super::orst::Sorter;
+use std::cmp::Ordering;
 
 pub struct InsertionSort;
 
 impl<T> Sorter<T> for InsertionSort {
-    fn sort(&self, slice: &mut [T])
-    where
-        T: Ord,
-    {
+    fn sort_by(&self, slice: &mut [T], mut compare: impl FnMut(&T, &T) -> Ordering) {
         for unsorted in 1..slice.len() {
             // slice[unsorted..] is not sorted
             // take slice[unsorted] and place in sorted locationin slice[..=unsorted]
 
             let mut i = unsorted;
 
-            while i > 0 && slice[i - 1] > slice[i] {
+            while i > 0 && compare(&slice[i - 1], &slice[i]) == Ordering::Greater {
                 slice.swap(i - 1, i);
                 i -= 1;
             }
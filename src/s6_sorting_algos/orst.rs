@@ -1,16 +1,27 @@
+use std::cmp::Ordering;
+
+// Sorters only have to implement sort_by. The comparator says how two items compare, which is how
+// you sort by a field, in reverse, or sort things that aren't Ord at all, like floats with
+// f64::total_cmp. sort and sort_by_key are just particular comparators
 pub trait Sorter<T> {
+    fn sort_by(&self, slice: &mut [T], compare: impl FnMut(&T, &T) -> Ordering);
+
+    fn sort_by_key<K: Ord>(&self, slice: &mut [T], mut key: impl FnMut(&T) -> K) {
+        self.sort_by(slice, |a, b| key(a).cmp(&key(b)));
+    }
+
     fn sort(&self, slice: &mut [T])
     where
-        T: Ord;
+        T: Ord,
+    {
+        self.sort_by(slice, T::cmp);
+    }
 }
 
 pub struct StdSorter;
 impl<T> Sorter<T> for StdSorter {
-    fn sort(&self, slice: &mut [T])
-    where
-        T: Ord,
-    {
-        slice.sort();
+    fn sort_by(&self, slice: &mut [T], compare: impl FnMut(&T, &T) -> Ordering) {
+        slice.sort_by(compare);
     }
 }
 
@@ -25,6 +36,28 @@ pub mod tests {
 
         assert_eq!(things, &[1, 2, 3, 4]);
     }
+
+    fn sorts_with_comparators<S: Sorter<(&'static str, f64)>>(sorter: S) {
+        let names = |things: &[(&'static str, f64)]| things.iter().map(|t| t.0).collect::<Vec<_>>();
+
+        // f64 isn't Ord, NaN sorts last with total_cmp
+        let mut things = vec![("b", 2.5), ("a", -1.0), ("c", f64::NAN), ("d", 0.0)];
+        sorter.sort_by(&mut things, |x, y| x.1.total_cmp(&y.1));
+        assert_eq!(names(&things), ["a", "d", "b", "c"]);
+
+        sorter.sort_by_key(&mut things, |t| std::cmp::Reverse(t.0));
+        assert_eq!(names(&things), ["d", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn every_sorter_takes_a_comparator() {
+        use super::super::{bubble::BubbleSort, insertion::InsertionSort, quick::QuickSort};
+
+        sorts_with_comparators(StdSorter);
+        sorts_with_comparators(BubbleSort);
+        sorts_with_comparators(InsertionSort);
+        sorts_with_comparators(QuickSort);
+    }
 }
 
 pub fn tests() {}
@@ -1,12 +1,13 @@
 use super::orst::Sorter;
+use std::cmp::Ordering;
 
 pub struct QuickSort;
 
-fn quicksort<T: Ord>(slice: &mut [T]) {
+fn quicksort<T>(slice: &mut [T], compare: &mut impl FnMut(&T, &T) -> Ordering) {
     match slice.len() {
         0 | 1 => return,
         2 => {
-            if slice[0] > slice[1] {
+            if compare(&slice[0], &slice[1]) == Ordering::Greater {
                 slice.swap(0, 1);
             }
             return;
@@ -19,10 +20,10 @@ fn quicksort<T: Ord>(slice: &mut [T]) {
     let (mut left, mut right) = (0 as i32, (rest.len() - 1) as i32);
 
     while left <= right && left < rest.len() as i32 && right >= 0 {
-        if &rest[left as usize] <= pivot {
+        if compare(&rest[left as usize], pivot) != Ordering::Greater {
             // already on the correct side
             left += 1;
-        } else if &rest[right as usize] > pivot {
+        } else if compare(&rest[right as usize], pivot) == Ordering::Greater {
             right -= 1;
         } else {
             rest.swap(left as usize, right as usize);
@@ -35,23 +36,20 @@ fn quicksort<T: Ord>(slice: &mut [T]) {
     slice.swap(0, left as usize);
 
     let (left, right) = slice.split_at_mut(left as usize);
-    quicksort(left);
-    quicksort(&mut right[1..]);
+    quicksort(left, compare);
+    quicksort(&mut right[1..], compare);
 }
 
 impl<T> Sorter<T> for QuickSort {
-    fn sort(&self, slice: &mut [T])
-    where
-        T: Ord,
-    {
-        quicksort(slice);
+    fn sort_by(&self, slice: &mut [T], mut compare: impl FnMut(&T, &T) -> Ordering) {
+        quicksort(slice, &mut compare);
         for unsorted in 1..slice.len() {
             // slice[unsorted..] is not sorted
             // take slice[unsorted] and place in sorted locationin slice[..=unsorted]
 
             let mut i = unsorted;
 
-            while i > 0 && slice[i - 1] > slice[i] {
+            while i > 0 && compare(&slice[i - 1], &slice[i]) == Ordering::Greater {
                 slice.swap(i - 1, i);
                 i -= 1;
             }